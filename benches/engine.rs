@@ -0,0 +1,125 @@
+use athenic_demodulator::{
+    additive_engine::{AdditiveEngine, MAX_HARMONICS},
+    slew_limiter::{SlewLimiter, DEFAULT_SLEW_MS},
+    BasicGainMode, SlewMode, SynthesisBackend,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const SAMPLE_RATE: f32 = 44100.0;
+const BLOCK_SIZE: usize = 512;
+
+fn sawtooth_engine() -> AdditiveEngine {
+    let mut engine = AdditiveEngine::default();
+    let mut amps = [0.0; MAX_HARMONICS];
+    for (i, amp) in amps.iter_mut().enumerate() {
+        *amp = 1.0 / (i + 1) as f32;
+    }
+    engine.submit_amplitudes(&amps, &amps);
+    engine
+}
+
+/// A spectrum with only every `spacing`th partial sounding.
+fn sparse_engine(spacing: usize) -> AdditiveEngine {
+    let mut engine = AdditiveEngine::default();
+    let mut amps = [0.0; MAX_HARMONICS];
+    for (i, amp) in amps.iter_mut().enumerate().step_by(spacing) {
+        *amp = 1.0 / (i + 1) as f32;
+    }
+    engine.submit_amplitudes(&amps, &amps);
+    engine
+}
+
+fn harmonic_freqs(fundamental: f64) -> [f64; MAX_HARMONICS] {
+    let mut i_freqs = [0.0; MAX_HARMONICS];
+    for (n, freq) in i_freqs.iter_mut().enumerate() {
+        *freq = fundamental * (n + 1) as f64;
+    }
+    i_freqs
+}
+
+fn backends(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_samples");
+
+    let mut slew = SlewLimiter::default();
+    slew.update(
+        SAMPLE_RATE,
+        true,
+        &SlewMode::Linear,
+        DEFAULT_SLEW_MS,
+        DEFAULT_SLEW_MS,
+        0.0,
+    );
+
+    // 20 Hz keeps about half of the partials below Nyquist; 440 Hz leaves almost all above it
+    for fundamental in [20.0, 440.0] {
+        let i_freqs = harmonic_freqs(fundamental);
+
+        for (name, backend) in [
+            ("oscillator bank", SynthesisBackend::OscillatorBank),
+            ("inverse fft", SynthesisBackend::InverseFft),
+        ] {
+            let mut engine = sawtooth_engine();
+            let mut out_l = [0.0; BLOCK_SIZE];
+            let mut out_r = [0.0; BLOCK_SIZE];
+
+            group.bench_function(BenchmarkId::new(name, fundamental), |b| {
+                b.iter(|| {
+                    engine.generate_samples(
+                        &i_freqs,
+                        SAMPLE_RATE,
+                        &mut out_l,
+                        &mut out_r,
+                        &BasicGainMode::Sawtooth,
+                        &slew,
+                        &backend,
+                    )
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+/// The oscillator bank only runs partials that are sounding, so sparser spectra cost less.
+fn sparse_spectra(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sparse spectra");
+
+    let mut slew = SlewLimiter::default();
+    slew.update(
+        SAMPLE_RATE,
+        true,
+        &SlewMode::Linear,
+        DEFAULT_SLEW_MS,
+        DEFAULT_SLEW_MS,
+        0.0,
+    );
+    // every partial from the second up within the audible range, so silence decides what is
+    // skipped
+    let i_freqs = harmonic_freqs(SAMPLE_RATE as f64 / 2.0 / MAX_HARMONICS as f64);
+
+    for spacing in [1, 4, 16, 64] {
+        let mut engine = sparse_engine(spacing);
+        let mut out_l = [0.0; BLOCK_SIZE];
+        let mut out_r = [0.0; BLOCK_SIZE];
+
+        group.bench_function(BenchmarkId::new("every nth partial", spacing), |b| {
+            b.iter(|| {
+                engine.generate_samples(
+                    &i_freqs,
+                    SAMPLE_RATE,
+                    &mut out_l,
+                    &mut out_r,
+                    &BasicGainMode::Sawtooth,
+                    &slew,
+                    &SynthesisBackend::OscillatorBank,
+                )
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, backends, sparse_spectra);
+criterion_main!(benches);
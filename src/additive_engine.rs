@@ -1,6 +1,18 @@
-use crate::BasicGainMode;
+use crate::{
+    ifft_resynth::IfftResynth, slew_limiter::SlewLimiter, BasicGainMode, SynthesisBackend,
+};
 
-pub const MAX_HARMONICS: usize = 512;
+/// The most partials any voice can play. Only the partials within a voice's budget are processed
+/// or updated, so the ceiling mostly costs memory: about 124 KB of per-partial state in each
+/// engine, or 16 MB across every unison copy of every voice.
+pub const MAX_HARMONICS: usize = 2048;
+
+/// Each partial's phase, in cycles, when a voice starts. Spreading the phases out keeps the peak
+/// level down for spectra without phase data. Spread as `512/(n+1)` cycles so existing patches
+/// keep their phase layout.
+pub fn initial_phase(partial: usize) -> f64 {
+    512.0 / (partial + 1) as f64
+}
 
 pub struct AdditiveEngine {
     pub phases: [f64; MAX_HARMONICS],
@@ -8,6 +20,28 @@ pub struct AdditiveEngine {
     pub amp_r: [f32; MAX_HARMONICS],
     last_amp_l: [f32; MAX_HARMONICS],
     last_amp_r: [f32; MAX_HARMONICS],
+    target_amp_l: [f32; MAX_HARMONICS],
+    target_amp_r: [f32; MAX_HARMONICS],
+    amp_step_l: [f32; MAX_HARMONICS],
+    amp_step_r: [f32; MAX_HARMONICS],
+    ramp_remaining: usize,
+    /// How far each phase has been moved from its free-running value by received phase data.
+    phase_offsets: [f64; MAX_HARMONICS],
+    phase_steps: [f64; MAX_HARMONICS],
+    phase_glide_remaining: usize,
+    /// Spectral tilt as an exponent of the partial number, so 1 is +6 dB per octave.
+    tilt: f32,
+    /// Each partial's basic gain with the tilt applied, for the settings they were computed for.
+    gains: [f32; MAX_HARMONICS],
+    gains_sawtooth: bool,
+    gains_tilt: f32,
+    gains_partials: usize,
+    /// Partials from the first up to this one are processed, and the rest left silent.
+    partial_budget: usize,
+    /// The partials the oscillator bank runs sample by sample, rebuilt every block.
+    active: [u16; MAX_HARMONICS],
+    active_len: usize,
+    ifft: IfftResynth,
 }
 
 impl Default for AdditiveEngine {
@@ -18,14 +52,199 @@ impl Default for AdditiveEngine {
             amp_r: [0.0; MAX_HARMONICS],
             last_amp_l: [0.0; MAX_HARMONICS],
             last_amp_r: [0.0; MAX_HARMONICS],
+            target_amp_l: [0.0; MAX_HARMONICS],
+            target_amp_r: [0.0; MAX_HARMONICS],
+            amp_step_l: [0.0; MAX_HARMONICS],
+            amp_step_r: [0.0; MAX_HARMONICS],
+            ramp_remaining: 0,
+            phase_offsets: [0.0; MAX_HARMONICS],
+            phase_steps: [0.0; MAX_HARMONICS],
+            phase_glide_remaining: 0,
+            tilt: 0.0,
+            gains: [1.0; MAX_HARMONICS],
+            gains_sawtooth: false,
+            gains_tilt: 0.0,
+            gains_partials: 0,
+            partial_budget: MAX_HARMONICS,
+            active: [0; MAX_HARMONICS],
+            active_len: 0,
+            ifft: IfftResynth::default(),
         }
     }
 }
 
 impl AdditiveEngine {
     pub fn submit_amplitudes(&mut self, amp_l: &[f32], amp_r: &[f32]) {
-        self.amp_l.copy_from_slice(amp_l);
-        self.amp_r.copy_from_slice(amp_r);
+        let budget = ..self.partial_budget;
+        self.amp_l[budget].copy_from_slice(&amp_l[budget]);
+        self.amp_r[budget].copy_from_slice(&amp_r[budget]);
+        self.ramp_remaining = 0;
+    }
+
+    /// Moves the amplitudes linearly to `amp_l`/`amp_r` over the next `len` samples.
+    #[allow(clippy::needless_range_loop)]
+    pub fn submit_amplitude_ramp(&mut self, amp_l: &[f32], amp_r: &[f32], len: usize) {
+        if len == 0 {
+            self.submit_amplitudes(amp_l, amp_r);
+            return;
+        }
+
+        let budget = ..self.partial_budget;
+        self.target_amp_l[budget].copy_from_slice(&amp_l[budget]);
+        self.target_amp_r[budget].copy_from_slice(&amp_r[budget]);
+        let scale = 1.0 / len as f32;
+        for i in 0..self.partial_budget {
+            self.amp_step_l[i] = (self.target_amp_l[i] - self.amp_l[i]) * scale;
+            self.amp_step_r[i] = (self.target_amp_r[i] - self.amp_r[i]) * scale;
+        }
+        self.ramp_remaining = len;
+    }
+
+    #[allow(clippy::needless_range_loop)]
+    fn advance_ramp(&mut self, samples: usize) {
+        if self.ramp_remaining == 0 {
+            return;
+        }
+
+        if samples >= self.ramp_remaining {
+            let budget = ..self.partial_budget;
+            self.amp_l[budget].copy_from_slice(&self.target_amp_l[budget]);
+            self.amp_r[budget].copy_from_slice(&self.target_amp_r[budget]);
+            self.ramp_remaining = 0;
+            return;
+        }
+
+        let samples_f32 = samples as f32;
+        for i in 0..self.partial_budget {
+            self.amp_l[i] += self.amp_step_l[i] * samples_f32;
+            self.amp_r[i] += self.amp_step_r[i] * samples_f32;
+        }
+        self.ramp_remaining -= samples;
+    }
+
+    /// Moves each partial to the phase in `phases`, in cycles relative to a harmonic series that
+    /// starts in phase, either at once or spread over the next `glide_len` samples. Phases always
+    /// take the shortest way round.
+    #[allow(clippy::needless_range_loop)]
+    pub fn submit_phases(&mut self, phases: &[f32], glide_len: usize) {
+        for i in 0..self.partial_budget {
+            let target = phases[i] as f64 - initial_phase(i);
+            let error = (target - self.phase_offsets[i] + 0.5).rem_euclid(1.0) - 0.5;
+
+            if glide_len == 0 {
+                self.phases[i] += error;
+                self.phase_offsets[i] += error;
+            } else {
+                self.phase_steps[i] = error / glide_len as f64;
+            }
+        }
+        self.phase_glide_remaining = glide_len;
+    }
+
+    #[allow(clippy::needless_range_loop)]
+    fn advance_phase_glide(&mut self, samples: usize) {
+        if self.phase_glide_remaining == 0 {
+            return;
+        }
+
+        let samples = samples.min(self.phase_glide_remaining);
+        for i in 0..self.partial_budget {
+            let step = self.phase_steps[i] * samples as f64;
+            self.phases[i] += step;
+            self.phase_offsets[i] += step;
+        }
+        self.phase_glide_remaining -= samples;
+    }
+
+    pub fn reset_phase_offsets(&mut self) {
+        self.phase_offsets.fill(0.0);
+        self.phase_glide_remaining = 0;
+    }
+
+    /// Limits processing to the first `budget` partials. Partials leaving the budget are silenced
+    /// and stop being updated, so ones rejoining it start out silent and still.
+    pub fn partial_budget(&self) -> usize {
+        self.partial_budget
+    }
+
+    pub fn set_partial_budget(&mut self, budget: usize) {
+        let budget = budget.min(MAX_HARMONICS);
+        if budget < self.partial_budget {
+            let leaving = budget..self.partial_budget;
+            for amps in [
+                &mut self.amp_l,
+                &mut self.amp_r,
+                &mut self.last_amp_l,
+                &mut self.last_amp_r,
+                &mut self.target_amp_l,
+                &mut self.target_amp_r,
+                &mut self.amp_step_l,
+                &mut self.amp_step_r,
+            ] {
+                amps[leaving.clone()].fill(0.0);
+            }
+            self.phase_steps[leaving].fill(0.0);
+        }
+        self.partial_budget = budget;
+    }
+
+    /// Takes over `leader`'s amplitudes, ramps and received phases, so an engine that starts
+    /// sounding mid-note joins in where the others are. Its own free-running phases are kept, moved
+    /// by the difference in received phase.
+    pub fn follow(&mut self, leader: &AdditiveEngine) {
+        self.set_partial_budget(leader.partial_budget);
+        let budget = ..self.partial_budget;
+
+        self.amp_l[budget].copy_from_slice(&leader.amp_l[budget]);
+        self.amp_r[budget].copy_from_slice(&leader.amp_r[budget]);
+        self.last_amp_l[budget].copy_from_slice(&leader.last_amp_l[budget]);
+        self.last_amp_r[budget].copy_from_slice(&leader.last_amp_r[budget]);
+        self.target_amp_l[budget].copy_from_slice(&leader.target_amp_l[budget]);
+        self.target_amp_r[budget].copy_from_slice(&leader.target_amp_r[budget]);
+        self.amp_step_l[budget].copy_from_slice(&leader.amp_step_l[budget]);
+        self.amp_step_r[budget].copy_from_slice(&leader.amp_step_r[budget]);
+        self.ramp_remaining = leader.ramp_remaining;
+
+        for ((phase, offset), leader_offset) in self.phases[budget]
+            .iter_mut()
+            .zip(&mut self.phase_offsets[budget])
+            .zip(&leader.phase_offsets[budget])
+        {
+            *phase += leader_offset - *offset;
+            *offset = *leader_offset;
+        }
+        self.phase_steps[budget].copy_from_slice(&leader.phase_steps[budget]);
+        self.phase_glide_remaining = leader.phase_glide_remaining;
+
+        self.tilt = leader.tilt;
+        self.ifft.reset();
+    }
+
+    pub fn set_tilt(&mut self, tilt: f32) {
+        self.tilt = tilt;
+    }
+
+    fn update_gains(&mut self, basic_gain_mode: &BasicGainMode) {
+        let sawtooth = *basic_gain_mode == BasicGainMode::Sawtooth;
+        if sawtooth == self.gains_sawtooth
+            && self.tilt == self.gains_tilt
+            && self.partial_budget <= self.gains_partials
+        {
+            return;
+        }
+
+        for (i, gain) in self.gains[..self.partial_budget].iter_mut().enumerate() {
+            let partial = (i + 1) as f32;
+            let basic_gain = if sawtooth {
+                (1.0 / partial).sqrt()
+            } else {
+                1.0
+            };
+            *gain = basic_gain * partial.powf(self.tilt);
+        }
+        self.gains_sawtooth = sawtooth;
+        self.gains_tilt = self.tilt;
+        self.gains_partials = self.partial_budget;
     }
 
     pub fn reset_slew_tracking(&mut self) {
@@ -33,7 +252,11 @@ impl AdditiveEngine {
         self.last_amp_r.fill(0.0);
     }
 
-    #[allow(clippy::needless_range_loop)] // autovectorization
+    pub fn reset_resynthesis(&mut self) {
+        self.ifft.reset();
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn generate_samples(
         &mut self,
         i_freqs: &[f64; MAX_HARMONICS],
@@ -41,7 +264,82 @@ impl AdditiveEngine {
         out_l: &mut [f32],
         out_r: &mut [f32],
         basic_gain_mode: &BasicGainMode,
-        slew_limiting: bool,
+        slew: &SlewLimiter,
+        backend: &SynthesisBackend,
+    ) {
+        self.update_gains(basic_gain_mode);
+
+        match backend {
+            SynthesisBackend::OscillatorBank => {
+                self.ifft.reset();
+                self.generate_oscillator_bank(i_freqs, sample_rate, out_l, out_r, slew);
+            }
+            SynthesisBackend::InverseFft => {
+                // the inverse FFT only reads amplitudes and phases once per hop, so ramps move a
+                // block at a time
+                self.advance_ramp(out_l.len());
+                self.advance_phase_glide(out_l.len());
+                self.ifft.generate_samples(
+                    &mut self.phases,
+                    &self.amp_l,
+                    &self.amp_r,
+                    &mut self.last_amp_l,
+                    &mut self.last_amp_r,
+                    i_freqs,
+                    sample_rate,
+                    out_l,
+                    out_r,
+                    &self.gains,
+                    self.partial_budget,
+                    slew,
+                );
+            }
+        }
+    }
+
+    /// Lists the partials the oscillator bank has to run sample by sample for the next `samples`
+    /// samples: those in the audible range that are sounding, ramping in, or still slewing out.
+    /// Everything else in the budget only has its phase moved on, all at once.
+    #[allow(clippy::needless_range_loop)]
+    fn update_active_partials(
+        &mut self,
+        i_freqs: &[f64; MAX_HARMONICS],
+        sample_rate: f32,
+        samples: usize,
+    ) {
+        let sr_f64 = sample_rate as f64;
+        let glide_samples = samples.min(self.phase_glide_remaining) as f64;
+        let ramping = self.ramp_remaining > 0;
+
+        self.active_len = 0;
+        for i in 0..self.partial_budget {
+            let freq = i_freqs[i];
+            let audible = freq >= 20.0 && freq <= sr_f64 / 2.0;
+            let sounding = self.amp_l[i] != 0.0
+                || self.amp_r[i] != 0.0
+                || self.last_amp_l[i] != 0.0
+                || self.last_amp_r[i] != 0.0
+                || (ramping && (self.target_amp_l[i] != 0.0 || self.target_amp_r[i] != 0.0));
+
+            if audible && sounding {
+                self.active[self.active_len] = i as u16;
+                self.active_len += 1;
+            } else {
+                let glide = self.phase_steps[i] * glide_samples;
+                self.phases[i] = (self.phases[i] + freq / sr_f64 * samples as f64 + glide) % 2.0;
+                self.phase_offsets[i] += glide;
+            }
+        }
+    }
+
+    #[allow(clippy::needless_range_loop)] // autovectorization
+    fn generate_oscillator_bank(
+        &mut self,
+        i_freqs: &[f64; MAX_HARMONICS],
+        sample_rate: f32,
+        out_l: &mut [f32],
+        out_r: &mut [f32],
+        slew: &SlewLimiter,
     ) {
         assert_eq!(
             out_l.len(),
@@ -50,52 +348,166 @@ impl AdditiveEngine {
         );
 
         let sr_f64 = sample_rate as f64;
+        self.update_active_partials(i_freqs, sample_rate, out_l.len());
 
         for n in 0..out_l.len() {
+            // ramps and phase glides are stepped per partial, so silent partials are skipped,
+            // and finish exactly on their targets
+            let ramping = self.ramp_remaining > 0;
+            let gliding = self.phase_glide_remaining > 0;
+
             let mut samp_l: f32 = 0.0;
             let mut samp_r: f32 = 0.0;
 
-            for i in 0..MAX_HARMONICS {
+            for &i in &self.active[..self.active_len] {
+                let i = i as usize;
+                if ramping {
+                    self.amp_l[i] += self.amp_step_l[i];
+                    self.amp_r[i] += self.amp_step_r[i];
+                }
+
                 let freq = i_freqs[i];
-                let step = freq / sr_f64;
-                let phase = &mut self.phases[i];
+                let mut step = freq / sr_f64;
+                if gliding {
+                    step += self.phase_steps[i];
+                    self.phase_offsets[i] += self.phase_steps[i];
+                }
 
+                let phase = &mut self.phases[i];
                 *phase += step;
                 if *phase > 2.0 {
                     *phase -= 2.0;
                 }
 
-                if freq >= 20.0 && freq <= sr_f64 / 2.0 {
-                    let v = f64::sin(*phase * std::f64::consts::TAU);
+                let v = f64::sin(*phase * std::f64::consts::TAU);
 
-                    let mut amp_l = self.amp_l[i];
-                    let mut amp_r = self.amp_r[i];
+                let amp_l = slew.next(i, self.last_amp_l[i], self.amp_l[i], 1);
+                let amp_r = slew.next(i, self.last_amp_r[i], self.amp_r[i], 1);
 
-                    if slew_limiting {
-                        let slew_threshold = 12.5 / sample_rate;
-                        let last_amp_l = self.last_amp_l[i];
-                        let last_amp_r = self.last_amp_r[i];
-                        let delta_amp_l = amp_l - last_amp_l;
-                        let delta_amp_r = amp_r - last_amp_r;
-                        amp_l = last_amp_l + delta_amp_l.clamp(-slew_threshold, slew_threshold);
-                        amp_r = last_amp_r + delta_amp_r.clamp(-slew_threshold, slew_threshold);
-                    }
+                self.last_amp_l[i] = amp_l;
+                self.last_amp_r[i] = amp_r;
 
-                    self.last_amp_l[i] = amp_l;
-                    self.last_amp_r[i] = amp_r;
+                let gain = self.gains[i];
 
-                    let basic_gain = match basic_gain_mode {
-                        BasicGainMode::Flat => 1.0,
-                        BasicGainMode::Sawtooth => (1.0 / (i as f32 + 1.0)).sqrt(),
-                    };
+                samp_l += v as f32 * amp_l * gain;
+                samp_r += v as f32 * amp_r * gain;
+            }
 
-                    samp_l += v as f32 * amp_l * basic_gain;
-                    samp_r += v as f32 * amp_r * basic_gain;
+            if ramping {
+                self.ramp_remaining -= 1;
+                if self.ramp_remaining == 0 {
+                    self.amp_l.copy_from_slice(&self.target_amp_l);
+                    self.amp_r.copy_from_slice(&self.target_amp_r);
                 }
             }
+            if gliding {
+                self.phase_glide_remaining -= 1;
+            }
 
             out_l[n] += samp_l;
             out_r[n] += samp_r;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partials_rejoin_the_budget_silent() {
+        let mut engine = AdditiveEngine::default();
+        engine.set_partial_budget(8);
+        engine.submit_amplitudes(&[1.0; MAX_HARMONICS], &[1.0; MAX_HARMONICS]);
+        assert_eq!(engine.amp_l[..8], [1.0; 8]);
+        assert_eq!(engine.amp_l[8], 0.0);
+
+        engine.set_partial_budget(4);
+        engine.set_partial_budget(8);
+        assert_eq!(engine.amp_l[..4], [1.0; 4]);
+        assert_eq!(engine.amp_l[4..8], [0.0; 4]);
+        assert_eq!(engine.amp_r[4..8], [0.0; 4]);
+    }
+
+    #[test]
+    fn follower_keeps_its_own_phases() {
+        let mut leader = AdditiveEngine::default();
+        let mut follower = AdditiveEngine::default();
+        for (i, (leader, follower)) in leader
+            .phases
+            .iter_mut()
+            .zip(&mut follower.phases)
+            .enumerate()
+        {
+            *leader = initial_phase(i);
+            *follower = initial_phase(i) + 0.25;
+        }
+
+        leader.submit_phases(&[0.5; MAX_HARMONICS], 0);
+        follower.follow(&leader);
+        for (leader, follower) in leader.phases.iter().zip(&follower.phases) {
+            assert!((follower - leader - 0.25).abs() < 1e-9);
+        }
+
+        // both take the same path to the next phases
+        leader.submit_phases(&[0.0; MAX_HARMONICS], 0);
+        follower.submit_phases(&[0.0; MAX_HARMONICS], 0);
+        for (leader, follower) in leader.phases.iter().zip(&follower.phases) {
+            assert!((follower - leader - 0.25).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn silent_partials_are_skipped_without_changing_the_output() {
+        const BUDGET: usize = 64;
+        const BLOCK: usize = 32;
+        let sample_rate = 48000.0;
+
+        let mut engine = AdditiveEngine::default();
+        engine.set_partial_budget(BUDGET);
+        let mut amp_l = [0.0; MAX_HARMONICS];
+        let mut amp_r = [0.0; MAX_HARMONICS];
+        for i in (0..BUDGET).step_by(4) {
+            amp_l[i] = 1.0 / (i + 1) as f32;
+            amp_r[i] = -0.5 / (i + 1) as f32;
+        }
+        engine.submit_amplitudes(&amp_l, &amp_r);
+        let i_freqs = std::array::from_fn(|i| 110.0 * (i + 1) as f64);
+        let mut slew = SlewLimiter::default();
+        slew.update(sample_rate, false, &crate::SlewMode::Linear, 0.0, 0.0, 0.0);
+
+        // every partial in the budget, sample by sample
+        let mut phases = engine.phases;
+        let mut expected_l = [0.0f32; 4 * BLOCK];
+        let mut expected_r = [0.0f32; 4 * BLOCK];
+        for (l, r) in expected_l.iter_mut().zip(&mut expected_r) {
+            for i in 0..BUDGET {
+                phases[i] += i_freqs[i] / sample_rate as f64;
+                if phases[i] > 2.0 {
+                    phases[i] -= 2.0;
+                }
+                let v = f64::sin(phases[i] * std::f64::consts::TAU) as f32;
+                *l += v * amp_l[i];
+                *r += v * amp_r[i];
+            }
+        }
+
+        let mut out_l = [0.0; 4 * BLOCK];
+        let mut out_r = [0.0; 4 * BLOCK];
+        for (block_l, block_r) in out_l.chunks_mut(BLOCK).zip(out_r.chunks_mut(BLOCK)) {
+            engine.generate_samples(
+                &i_freqs,
+                sample_rate,
+                block_l,
+                block_r,
+                &BasicGainMode::Flat,
+                &slew,
+                &SynthesisBackend::OscillatorBank,
+            );
+            assert_eq!(engine.active_len, BUDGET / 4);
+        }
+
+        assert_eq!(out_l, expected_l);
+        assert_eq!(out_r, expected_r);
+    }
+}
@@ -0,0 +1,425 @@
+//! Offline renderer: runs a CV WAV file through the demodulator and voice.
+
+use athenic_demodulator::{
+    synth::{NoteEventIo, Synth, SynthSettings},
+    tuning::{Tuning, TuningFiles},
+    CvSource, SynthParams,
+};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use nih_plug::prelude::NoteEvent;
+use std::{env, process};
+
+const USAGE: &str = "\
+usage: athenic-render <input.wav> <output.wav> [options]
+
+options:
+  --main <file.wav>                 main audio to mix under the synth at the dry level,
+                                    with <input.wav> read as sidechain CV (--cv_source Sidechain)
+  --midi <file.mid>                 play the notes in a MIDI file
+  --note <note>:<start>:<length>    play a note, times in seconds (may be repeated)
+  --scl <file.scl>                  tune to a Scala scale
+  --kbm <file.kbm>                  map the scale to keys with a Scala keyboard mapping
+  --<param id> <value>              set a synth parameter, e.g. --partial_count 256";
+
+const CHUNK_SIZE: usize = 512;
+
+/// How long to keep rendering after the input and the last event for notes to finish, so a note
+/// that is never released doesn't render forever.
+const MAX_TAIL_SECONDS: f64 = 30.0;
+
+enum Event {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: f32,
+    },
+    PitchBend {
+        channel: u8,
+        value: f32,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: f32,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: f32,
+    },
+    Controller {
+        channel: u8,
+        cc: u8,
+        value: f32,
+    },
+}
+
+impl Event {
+    fn to_note_event(&self, timing: u32) -> NoteEvent<()> {
+        match *self {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => NoteEvent::NoteOn {
+                timing,
+                voice_id: None,
+                channel,
+                note,
+                velocity,
+            },
+            Event::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => NoteEvent::NoteOff {
+                timing,
+                voice_id: None,
+                channel,
+                note,
+                velocity,
+            },
+            Event::PitchBend { channel, value } => NoteEvent::MidiPitchBend {
+                timing,
+                channel,
+                value,
+            },
+            Event::ChannelPressure { channel, pressure } => NoteEvent::MidiChannelPressure {
+                timing,
+                channel,
+                pressure,
+            },
+            Event::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => NoteEvent::PolyPressure {
+                timing,
+                voice_id: None,
+                channel,
+                note,
+                pressure,
+            },
+            Event::Controller { channel, cc, value } => NoteEvent::MidiCC {
+                timing,
+                channel,
+                cc,
+                value,
+            },
+        }
+    }
+}
+
+/// Feeds timed events to the synth one chunk at a time.
+struct Schedule {
+    events: Vec<(usize, Event)>,
+    next: usize,
+    chunk_start: usize,
+    chunk_end: usize,
+}
+
+impl NoteEventIo for Schedule {
+    fn next_event(&mut self) -> Option<NoteEvent<()>> {
+        match self.events.get(self.next) {
+            Some((time, event)) if *time < self.chunk_end => {
+                self.next += 1;
+                Some(event.to_note_event((time - self.chunk_start) as u32))
+            }
+            _ => None,
+        }
+    }
+
+    fn send_event(&mut self, _event: NoteEvent<()>) {}
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("error: {err}");
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Err(USAGE.to_string());
+    }
+
+    let (input_l, input_r, sample_rate) = read_wav(&args[0])?;
+
+    let params = SynthParams::default();
+    let mut settings = SynthSettings::from_params(&params);
+    let mut events = Vec::new();
+    let mut main_path = None;
+    let mut scl_path = None;
+    let mut kbm_path = None;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let name = option
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument: {option}\n\n{USAGE}"))?;
+        let value = options
+            .next()
+            .ok_or_else(|| format!("missing value for {option}"))?;
+
+        match name {
+            "main" => main_path = Some(value),
+            "scl" => scl_path = Some(value.as_str()),
+            "kbm" => kbm_path = Some(value.as_str()),
+            "midi" => events.extend(read_midi(value, sample_rate)?),
+            "note" => events.extend(parse_note(value, sample_rate)?),
+            id => settings.set(&params, id, value)?,
+        }
+    }
+    events.sort_by_key(|(time, _)| *time);
+
+    // in sidechain mode the input is CV only, and the main buffers carry the --main audio
+    let (mut out_l, mut out_r, mut sidechain) = match (&settings.cv_source, main_path) {
+        (CvSource::Main, None) => (input_l, input_r, None),
+        (CvSource::Main, Some(_)) => {
+            return Err("--main requires --cv_source Sidechain".to_string());
+        }
+        (CvSource::Sidechain, main_path) => {
+            let (mut main_l, mut main_r) = match main_path {
+                Some(path) => {
+                    let (main_l, main_r, main_rate) = read_wav(path)?;
+                    if main_rate != sample_rate {
+                        return Err(format!(
+                            "{path}: sample rate {main_rate} does not match the input's {sample_rate}"
+                        ));
+                    }
+                    (main_l, main_r)
+                }
+                None => (Vec::new(), Vec::new()),
+            };
+            main_l.resize(input_l.len(), 0.0);
+            main_r.resize(input_r.len(), 0.0);
+            (main_l, main_r, Some((input_l, input_r)))
+        }
+    };
+
+    let mut synth = Synth::default();
+    synth.set_sample_rate(sample_rate as f32);
+    synth.tuning = Tuning::from_files(&TuningFiles::load(scl_path, kbm_path)?)?;
+    synth.update_frame_size(&settings);
+    synth.reset();
+
+    // past the end of the input, the CV and main audio are silent, and rendering carries on
+    // until the latency is made up and every note has finished
+    let latency = synth.latency_samples() as usize;
+    let last_event = events.last().map_or(0, |(time, _)| time + 1);
+    let length = out_l.len().max(last_event) + latency;
+    let max_length = length + (MAX_TAIL_SECONDS * sample_rate as f64) as usize;
+
+    let mut schedule = Schedule {
+        events,
+        next: 0,
+        chunk_start: 0,
+        chunk_end: 0,
+    };
+    while schedule.chunk_start < length
+        || (!synth.voices.is_idle() && schedule.chunk_start < max_length)
+    {
+        schedule.chunk_end = schedule.chunk_start + CHUNK_SIZE;
+        let chunk = schedule.chunk_start..schedule.chunk_end;
+
+        if out_l.len() < chunk.end {
+            out_l.resize(chunk.end, 0.0);
+            out_r.resize(chunk.end, 0.0);
+        }
+        if let Some((cv_l, cv_r)) = &mut sidechain {
+            if cv_l.len() < chunk.end {
+                cv_l.resize(chunk.end, 0.0);
+                cv_r.resize(chunk.end, 0.0);
+            }
+        }
+
+        let chunk_sidechain = sidechain
+            .as_ref()
+            .map(|(cv_l, cv_r)| (&cv_l[chunk.clone()], &cv_r[chunk.clone()]));
+        synth.process(
+            &mut out_l[chunk.clone()],
+            &mut out_r[chunk],
+            chunk_sidechain,
+            &params,
+            &settings,
+            &mut schedule,
+        );
+
+        schedule.chunk_start = schedule.chunk_end;
+    }
+
+    // line the output up with the input
+    write_wav(&args[1], &out_l[latency..], &out_r[latency..], sample_rate)
+}
+
+/// Reads a WAV file as a pair of channels. Mono files are duplicated to both sides.
+fn read_wav(path: &str) -> Result<(Vec<f32>, Vec<f32>, u32), String> {
+    let mut reader = hound::WavReader::open(path).map_err(|err| format!("{path}: {err}"))?;
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()
+        }
+    }
+    .map_err(|err| format!("{path}: {err}"))?;
+
+    let channels = spec.channels as usize;
+    let left = samples.iter().step_by(channels).copied().collect();
+    let right = samples
+        .iter()
+        .skip(if channels > 1 { 1 } else { 0 })
+        .step_by(channels)
+        .copied()
+        .collect();
+
+    Ok((left, right, spec.sample_rate))
+}
+
+fn write_wav(path: &str, out_l: &[f32], out_r: &[f32], sample_rate: u32) -> Result<(), String> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let mut writer =
+        hound::WavWriter::create(path, spec).map_err(|err| format!("{path}: {err}"))?;
+    for (l, r) in out_l.iter().zip(out_r) {
+        writer
+            .write_sample(*l)
+            .and_then(|_| writer.write_sample(*r))
+            .map_err(|err| format!("{path}: {err}"))?;
+    }
+
+    writer.finalize().map_err(|err| format!("{path}: {err}"))
+}
+
+/// Parses a `<note>:<start>:<length>` note, with times in seconds.
+fn parse_note(spec: &str, sample_rate: u32) -> Result<[(usize, Event); 2], String> {
+    let invalid = || format!("invalid note: {spec} (expected <note>:<start>:<length>)");
+
+    let mut fields = spec.split(':');
+    let (Some(note), Some(start), Some(length), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(invalid());
+    };
+
+    let note: u8 = note.parse().map_err(|_| invalid())?;
+    let start: f64 = start.parse().map_err(|_| invalid())?;
+    let length: f64 = length.parse().map_err(|_| invalid())?;
+    if note > 127 || start < 0.0 || length < 0.0 {
+        return Err(invalid());
+    }
+
+    let to_samples = |seconds: f64| (seconds * sample_rate as f64).round() as usize;
+    Ok([
+        (
+            to_samples(start),
+            Event::NoteOn {
+                channel: 0,
+                note,
+                velocity: 1.0,
+            },
+        ),
+        (
+            to_samples(start + length),
+            Event::NoteOff {
+                channel: 0,
+                note,
+                velocity: 0.0,
+            },
+        ),
+    ])
+}
+
+/// Reads the note and pitch bend events from every track of a standard MIDI file.
+fn read_midi(path: &str, sample_rate: u32) -> Result<Vec<(usize, Event)>, String> {
+    let bytes = std::fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+    let smf = Smf::parse(&bytes).map_err(|err| format!("{path}: {err}"))?;
+
+    let mut track_events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            track_events.push((tick, event.kind));
+        }
+    }
+    track_events.sort_by_key(|(tick, _)| *tick);
+
+    let mut events = Vec::new();
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+    let mut us_per_beat = 500_000.0;
+    for (tick, kind) in track_events {
+        seconds += (tick - last_tick) as f64
+            * match smf.header.timing {
+                Timing::Metrical(ticks_per_beat) => {
+                    us_per_beat / 1_000_000.0 / ticks_per_beat.as_int() as f64
+                }
+                Timing::Timecode(fps, subframes) => 1.0 / (fps.as_f32() as f64 * subframes as f64),
+            };
+        last_tick = tick;
+        let time = (seconds * sample_rate as f64).round() as usize;
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                us_per_beat = tempo.as_int() as f64;
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                let event = match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => Event::NoteOn {
+                        channel,
+                        note: key.as_int(),
+                        velocity: vel.as_int() as f32 / 127.0,
+                    },
+                    MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => {
+                        Event::NoteOff {
+                            channel,
+                            note: key.as_int(),
+                            velocity: vel.as_int() as f32 / 127.0,
+                        }
+                    }
+                    MidiMessage::PitchBend { bend } => Event::PitchBend {
+                        channel,
+                        value: bend.0.as_int() as f32 / 16383.0,
+                    },
+                    MidiMessage::ChannelAftertouch { vel } => Event::ChannelPressure {
+                        channel,
+                        pressure: vel.as_int() as f32 / 127.0,
+                    },
+                    MidiMessage::Aftertouch { key, vel } => Event::PolyPressure {
+                        channel,
+                        note: key.as_int(),
+                        pressure: vel.as_int() as f32 / 127.0,
+                    },
+                    MidiMessage::Controller { controller, value } => Event::Controller {
+                        channel,
+                        cc: controller.as_int(),
+                        value: value.as_int() as f32 / 127.0,
+                    },
+                    _ => continue,
+                };
+                events.push((time, event));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(events)
+}
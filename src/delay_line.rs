@@ -0,0 +1,89 @@
+/// A stereo delay whose buffer is allocated up front, so the delay time can change while
+/// processing.
+pub struct DelayLine {
+    buffer_l: Vec<f32>,
+    buffer_r: Vec<f32>,
+    position: usize,
+    delay: usize,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer_l: vec![0.0; max_delay + 1],
+            buffer_r: vec![0.0; max_delay + 1],
+            position: 0,
+            delay: 0,
+        }
+    }
+
+    /// Resizes the buffer, clearing it. This allocates, so it has to happen outside of processing.
+    pub fn set_max_delay(&mut self, max_delay: usize) {
+        if max_delay + 1 != self.buffer_l.len() {
+            *self = Self {
+                delay: self.delay.min(max_delay),
+                ..Self::new(max_delay)
+            };
+        }
+    }
+
+    /// Sets the delay in samples, limited to the maximum the buffer holds.
+    pub fn set_delay(&mut self, delay: usize) {
+        self.delay = delay.min(self.buffer_l.len() - 1);
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer_l.fill(0.0);
+        self.buffer_r.fill(0.0);
+        self.position = 0;
+    }
+
+    /// Delays `buf_l`/`buf_r` in place.
+    pub fn process(&mut self, buf_l: &mut [f32], buf_r: &mut [f32]) {
+        let len = self.buffer_l.len();
+        for (l, r) in buf_l.iter_mut().zip(buf_r.iter_mut()) {
+            self.buffer_l[self.position] = *l;
+            self.buffer_r[self.position] = *r;
+
+            let read = (self.position + len - self.delay) % len;
+            *l = self.buffer_l[read];
+            *r = self.buffer_r[read];
+
+            self.position = (self.position + 1) % len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_across_buffers() {
+        let mut delay = DelayLine::new(8);
+        delay.set_delay(3);
+
+        let input: Vec<f32> = (1..=10).map(|n| n as f32).collect();
+        let mut buf_l = input.clone();
+        let mut buf_r: Vec<f32> = input.iter().map(|x| -x).collect();
+        let (first_l, second_l) = buf_l.split_at_mut(4);
+        let (first_r, second_r) = buf_r.split_at_mut(4);
+        delay.process(first_l, first_r);
+        delay.process(second_l, second_r);
+
+        let expected: Vec<f32> = [0.0; 3].iter().chain(&input[..7]).copied().collect();
+        assert_eq!(buf_l, expected);
+        assert_eq!(buf_r, expected.iter().map(|x| -x).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn limits_the_delay_to_the_buffer() {
+        let mut delay = DelayLine::new(2);
+        delay.set_delay(5);
+
+        let mut buf_l = [1.0, 2.0, 3.0, 4.0];
+        let mut buf_r = buf_l;
+        delay.process(&mut buf_l, &mut buf_r);
+        assert_eq!(buf_l, [0.0, 0.0, 1.0, 2.0]);
+    }
+}
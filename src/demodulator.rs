@@ -1,11 +1,73 @@
-pub const DEMOD_BLOCK_SIZE: usize = 1050; // 42Hz @ 44.1KHz s.r.
+pub const DEFAULT_FRAME_RATE: f32 = 42.0; // 1050 samples @ 44.1KHz s.r.
+pub const MIN_FRAME_RATE: f32 = 5.0;
 
-use crate::{additive_engine::MAX_HARMONICS, DistributionMode};
+/// Level of the sync marker that starts each frame when sync is enabled. This lies well outside
+/// of anything the floor/ceiling/bias params can decode, so it can't be mistaken for data.
+pub const SYNC_LEVEL: f32 = 8.0;
+const SYNC_THRESHOLD: f32 = 4.0;
+/// Consecutive frames without a marker before the demodulator considers itself unlocked.
+const SYNC_LOSS_FRAMES: usize = 2;
+/// Octaves per unit of a frequency ratio sample, so 0..1 covers harmonics up to 1024.
+pub const RATIO_OCTAVES: f32 = 10.0;
+
+use crate::{additive_engine::MAX_HARMONICS, transfer_curve::Transfer, CvLayout, DistributionMode};
+use std::ops::RangeInclusive;
+
+/// The length of a CV frame in samples.
+pub fn frame_size(sample_rate: f32, frame_rate: f32) -> usize {
+    ((sample_rate / frame_rate).round() as usize).max(1)
+}
+
+/// The (1-indexed) partial that the sample at `progress` through a frame maps onto.
+pub fn frame_harmonic(
+    progress: usize,
+    frame_size: usize,
+    distribution_mode: &DistributionMode,
+    harmonic_count: usize,
+    harmonic_offset: usize,
+) -> usize {
+    let harmonic = match distribution_mode {
+        DistributionMode::Exponential => f32::floor(f32::exp(
+            f32::ln(harmonic_count as f32) * (progress as f32) / frame_size as f32,
+        )) as usize,
+        DistributionMode::Linear => {
+            f32::floor((harmonic_count as f32) * (progress as f32) / (frame_size as f32)) as usize
+        }
+    } + harmonic_offset;
+
+    harmonic.min(MAX_HARMONICS)
+}
+
+/// The (1-indexed) partials a frame carries, from the offset up to the one its last sample maps
+/// onto.
+pub fn frame_partials(
+    frame_size: usize,
+    distribution_mode: &DistributionMode,
+    harmonic_count: usize,
+    harmonic_offset: usize,
+) -> RangeInclusive<usize> {
+    let last = frame_harmonic(
+        frame_size.saturating_sub(1),
+        frame_size,
+        distribution_mode,
+        harmonic_count,
+        harmonic_offset,
+    );
+    harmonic_offset.max(1)..=last
+}
+
+/// Decodes a frequency ratio sample, as passed through by the amplitude/ratio layout.
+pub fn decode_ratio(cv: f32) -> f64 {
+    f64::exp2(cv as f64 * RATIO_OCTAVES as f64)
+}
 
 pub struct CVDemodulator {
+    frame_size: usize,
     progress: usize,
     prev_harmonic: usize,
     sample_count: usize,
+    sync_locked: bool,
+    missed_syncs: usize,
     working_amp_l: [f32; MAX_HARMONICS],
     working_amp_r: [f32; MAX_HARMONICS],
 }
@@ -13,9 +75,12 @@ pub struct CVDemodulator {
 impl Default for CVDemodulator {
     fn default() -> Self {
         Self {
+            frame_size: frame_size(44100.0, DEFAULT_FRAME_RATE),
             progress: 0,
-            prev_harmonic: 1,
+            prev_harmonic: 0,
             sample_count: 0,
+            sync_locked: false,
+            missed_syncs: 0,
             working_amp_l: [0.0; MAX_HARMONICS],
             working_amp_r: [0.0; MAX_HARMONICS],
         }
@@ -23,12 +88,29 @@ impl Default for CVDemodulator {
 }
 
 impl CVDemodulator {
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    pub fn set_frame_size(&mut self, frame_size: usize) {
+        if frame_size != self.frame_size {
+            self.frame_size = frame_size;
+            self.reset();
+        }
+    }
+
+    /// Whether the stream's sync markers are currently being tracked.
+    pub fn is_sync_locked(&self) -> bool {
+        self.sync_locked
+    }
+
     pub fn reset(&mut self) {
         self.progress = 0;
-        self.prev_harmonic = 1;
+        self.prev_harmonic = 0;
         self.sample_count = 0;
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn submit_samples(
         &mut self,
         in_l: &[f32],
@@ -36,9 +118,9 @@ impl CVDemodulator {
         distribution_mode: &DistributionMode,
         harmonic_count: usize,
         harmonic_offset: usize,
-        floor: f32,
-        ceiling: f32,
-        bias: f32,
+        transfer: &Transfer,
+        layout: &CvLayout,
+        sync: bool,
     ) -> Option<([f32; MAX_HARMONICS], [f32; MAX_HARMONICS])> {
         assert_eq!(
             in_l.len(),
@@ -48,77 +130,204 @@ impl CVDemodulator {
 
         let mut amps = None;
 
-        if self.progress >= DEMOD_BLOCK_SIZE {
+        if self.progress >= self.frame_size {
             self.progress = 0;
             self.working_amp_l.fill(0.0);
             self.working_amp_r.fill(0.0);
-            self.prev_harmonic = 1;
+            self.prev_harmonic = 0;
+        }
+
+        if !sync {
+            self.sync_locked = false;
         }
 
         for n in 0..in_l.len() {
-            let mut l = in_l[n] + bias;
-            if l > ceiling {
-                l = 0.0;
-            }
-            if l < floor {
-                l = 0.0;
-            }
-            let mut r = in_r[n] + bias;
-            if r > ceiling {
-                r = 0.0;
-            }
-            if r < floor {
-                r = 0.0;
+            if sync {
+                let marker = in_l[n].abs() >= SYNC_THRESHOLD && in_r[n].abs() >= SYNC_THRESHOLD;
+                if marker {
+                    if self.progress != 0 {
+                        // realign, dropping the partial frame
+                        self.working_amp_l.fill(0.0);
+                        self.working_amp_r.fill(0.0);
+                    }
+                    self.progress = 1;
+                    self.prev_harmonic = 0;
+                    self.sample_count = 0;
+                    self.sync_locked = true;
+                    self.missed_syncs = 0;
+                    continue;
+                } else if self.progress == 0 {
+                    self.missed_syncs += 1;
+                    if self.missed_syncs >= SYNC_LOSS_FRAMES {
+                        self.sync_locked = false;
+                    }
+                }
             }
 
-            let next_harmonic: usize = match distribution_mode {
-                DistributionMode::Exponential => f32::floor(f32::exp(
-                    f32::ln(harmonic_count as f32) * (self.progress as f32)
-                        / DEMOD_BLOCK_SIZE as f32,
-                )) as usize,
-                DistributionMode::Linear => f32::floor(
-                    (harmonic_count as f32) * (self.progress as f32) / (DEMOD_BLOCK_SIZE as f32),
-                ) as usize,
-            } + harmonic_offset;
-
-            for harmonic in
-                self.prev_harmonic.max(harmonic_offset).max(1)..=next_harmonic.min(MAX_HARMONICS)
-            {
-                let l = l * l * l.signum();
-                let r = r * r * r.signum();
-
-                if harmonic != self.prev_harmonic {
-                    self.sample_count = 0;
+            let (l, r) = match layout {
+                CvLayout::Stereo | CvLayout::DualResolution => {
+                    (transfer.decode(in_l[n]), transfer.decode(in_r[n]))
+                }
+                CvLayout::AmplitudePhase => (transfer.decode(in_l[n]), in_r[n] * 0.5),
+                // ratios are left as CV, to be decoded at full precision by `decode_ratio`, so
+                // samples sharing a partial average to their geometric mean
+                CvLayout::AmplitudeRatio => (transfer.decode(in_l[n]), in_r[n]),
+                CvLayout::MonoSum => {
+                    let mono = transfer.decode((in_l[n] + in_r[n]) * 0.5);
+                    (mono, mono)
+                }
+                CvLayout::MonoLeft => {
+                    let mono = transfer.decode(in_l[n]);
+                    (mono, mono)
+                }
+                CvLayout::MidSide => {
+                    let mid = transfer.decode(in_l[n]);
+                    let side = transfer.decode(in_r[n]).clamp(-mid.abs(), mid.abs());
+                    (mid + side, mid - side)
+                }
+            };
+
+            let next_harmonic = frame_harmonic(
+                self.progress,
+                self.frame_size,
+                distribution_mode,
+                harmonic_count,
+                harmonic_offset,
+            );
+            let first_harmonic = (self.prev_harmonic + 1).max(harmonic_offset).max(1);
+
+            if next_harmonic >= first_harmonic {
+                // this sample starts one or more new partials
+                for harmonic in first_harmonic..=next_harmonic {
                     self.working_amp_l[harmonic - 1] = l;
                     self.working_amp_r[harmonic - 1] = r;
-                } else {
-                    // moving average
-                    self.sample_count += 1;
-                    self.working_amp_l[harmonic - 1] =
-                        (self.working_amp_l[harmonic - 1] * (self.sample_count as f32) + l)
-                            / (self.sample_count as f32);
-                    self.working_amp_r[harmonic - 1] =
-                        (self.working_amp_r[harmonic - 1] * (self.sample_count as f32) + r)
-                            / (self.sample_count as f32);
                 }
-
-                self.prev_harmonic = harmonic;
+                self.sample_count = 1;
+                self.prev_harmonic = next_harmonic;
+            } else if self.prev_harmonic > 0 {
+                // moving average
+                let harmonic = self.prev_harmonic;
+                let count = self.sample_count as f32;
+                self.working_amp_l[harmonic - 1] =
+                    (self.working_amp_l[harmonic - 1] * count + l) / (count + 1.0);
+                self.working_amp_r[harmonic - 1] =
+                    (self.working_amp_r[harmonic - 1] * count + r) / (count + 1.0);
+                self.sample_count += 1;
             }
 
             self.progress += 1;
-            if self.progress >= DEMOD_BLOCK_SIZE {
-                let mut l = [0.0; MAX_HARMONICS];
-                let mut r = [0.0; MAX_HARMONICS];
-                l.copy_from_slice(&self.working_amp_l);
-                r.copy_from_slice(&self.working_amp_r);
-                amps = Some((l, r));
+            if self.progress >= self.frame_size {
+                // frames are only trusted while locked when sync is in use
+                if !sync || self.sync_locked {
+                    let mut l = [0.0; MAX_HARMONICS];
+                    let mut r = [0.0; MAX_HARMONICS];
+                    l.copy_from_slice(&self.working_amp_l);
+                    if *layout == CvLayout::DualResolution {
+                        // the right channel's partials continue on from the left's, so they only
+                        // land on partials the left leaves silent
+                        let upper = harmonic_count.min(MAX_HARMONICS);
+                        for (upper, right) in l[upper..].iter_mut().zip(&self.working_amp_r) {
+                            *upper += right;
+                        }
+                        r.copy_from_slice(&l);
+                    } else {
+                        r.copy_from_slice(&self.working_amp_r);
+                    }
+                    amps = Some((l, r));
+                }
                 self.progress = 0;
                 self.working_amp_l.fill(0.0);
                 self.working_amp_r.fill(0.0);
-                self.prev_harmonic = 1;
+                self.prev_harmonic = 0;
             }
         }
 
         amps
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transfer_curve::TRANSFER_TABLE_POINTS, TransferCurve};
+
+    const FRAME_SIZE: usize = 64;
+
+    /// Decodes a frame of constant samples through the linear curve.
+    fn decode(
+        layout: &CvLayout,
+        harmonic_count: usize,
+        left: f32,
+        right: f32,
+    ) -> ([f32; MAX_HARMONICS], [f32; MAX_HARMONICS]) {
+        let mut demodulator = CVDemodulator::default();
+        demodulator.set_frame_size(FRAME_SIZE);
+        demodulator
+            .submit_samples(
+                &[left; FRAME_SIZE],
+                &[right; FRAME_SIZE],
+                &DistributionMode::Linear,
+                harmonic_count,
+                0,
+                &Transfer {
+                    floor: -1.0,
+                    ceiling: 1.0,
+                    bias: 0.0,
+                    curve: &TransferCurve::Linear,
+                    table: &[0.0; TRANSFER_TABLE_POINTS],
+                    decibel_min: -96.0,
+                    decibel_max: 0.0,
+                },
+                layout,
+                false,
+            )
+            .expect("a whole frame was submitted")
+    }
+
+    #[test]
+    fn mid_side_keeps_polarity() {
+        let (l, r) = decode(&CvLayout::MidSide, 8, 0.5, 0.25);
+        assert_eq!((l[0], r[0]), (0.75, 0.25));
+
+        let (l, r) = decode(&CvLayout::MidSide, 8, 0.5, -0.75);
+        assert_eq!((l[0], r[0]), (0.0, 1.0));
+
+        let (l, r) = decode(&CvLayout::MidSide, 8, -0.5, 0.75);
+        assert_eq!((l[0], r[0]), (0.0, -1.0));
+    }
+
+    #[test]
+    fn dual_resolution_continues_on_the_right() {
+        let (l, r) = decode(&CvLayout::DualResolution, 8, 0.5, 0.25);
+        assert_eq!(l, r);
+        // with no offset, partial 8 is never reached on either channel
+        assert_eq!(l[..7], [0.5; 7]);
+        assert_eq!(l[7], 0.0);
+        assert_eq!(l[8..15], [0.25; 7]);
+        assert_eq!(l[15], 0.0);
+    }
+
+    #[test]
+    fn ratios_pass_through_as_cv_for_the_partials_reached() {
+        let (l, r) = decode(&CvLayout::AmplitudeRatio, 8, 0.25, 0.5);
+        assert_eq!(l[..7], [0.25; 7]);
+        assert_eq!(r[..7], [0.5; 7]);
+        assert_eq!(r[7], 0.0);
+        assert_eq!(
+            frame_partials(FRAME_SIZE, &DistributionMode::Linear, 8, 0),
+            1..=7
+        );
+        assert_eq!(decode_ratio(r[0]), 32.0);
+    }
+
+    #[test]
+    fn frame_partials_run_from_the_offset() {
+        for (mode, count, offset, partials) in [
+            (DistributionMode::Linear, 16, 8, 8..=23),
+            (DistributionMode::Linear, 64, 0, 1..=63),
+            (DistributionMode::Exponential, 8, 0, 1..=7),
+        ] {
+            assert_eq!(frame_partials(FRAME_SIZE, &mode, count, offset), partials);
+        }
+    }
+}
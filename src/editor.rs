@@ -0,0 +1,105 @@
+use crate::{
+    tuning::{Tuning, TuningFiles},
+    SynthParams,
+};
+use nih_plug::prelude::*;
+use nih_plug_egui::{
+    create_egui_editor,
+    egui::{self, Color32},
+    widgets::generic_ui::{self, GenericSlider},
+    EguiState,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+/// How often the editor redraws to pick up state from the audio thread.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn default_state() -> Arc<EguiState> {
+    EguiState::from_size(480, 640)
+}
+
+/// The tuning file paths being typed in, and the outcome of the last load.
+#[derive(Default)]
+struct TuningForm {
+    scl_path: String,
+    kbm_path: String,
+    status: String,
+}
+
+pub fn create(params: Arc<SynthParams>) -> Option<Box<dyn Editor>> {
+    create_egui_editor(
+        params.editor_state.clone(),
+        TuningForm::default(),
+        |_, _| {},
+        move |egui_ctx, setter, form| {
+            egui::CentralPanel::default().show(egui_ctx, |ui| {
+                sync_indicator(ui, &params);
+                ui.separator();
+                tuning_form(ui, &params, form);
+                ui.separator();
+                generic_ui::create(ui, params.clone(), setter, GenericSlider);
+            });
+            egui_ctx.request_repaint_after(REFRESH_INTERVAL);
+        },
+    )
+}
+
+/// Shows whether the demodulator is tracking the CV's sync markers.
+fn sync_indicator(ui: &mut egui::Ui, params: &SynthParams) {
+    ui.horizontal(|ui| {
+        ui.label("sync");
+        if !params.sync.value() {
+            ui.weak("off");
+        } else if params.sync_locked.load(Ordering::Relaxed) {
+            ui.colored_label(Color32::LIGHT_GREEN, "locked");
+        } else {
+            ui.colored_label(Color32::LIGHT_RED, "unlocked");
+        }
+    });
+}
+
+/// Loads a Scala scale and keyboard mapping, or goes back to 12-TET. Either path may be left
+/// empty for the default scale or mapping.
+fn tuning_form(ui: &mut egui::Ui, params: &SynthParams, form: &mut TuningForm) {
+    egui::Grid::new("tuning").num_columns(2).show(ui, |ui| {
+        ui.label("scale (.scl)");
+        ui.text_edit_singleline(&mut form.scl_path);
+        ui.end_row();
+        ui.label("mapping (.kbm)");
+        ui.text_edit_singleline(&mut form.kbm_path);
+        ui.end_row();
+    });
+
+    ui.horizontal(|ui| {
+        if ui.button("load tuning").clicked() {
+            let path = |path: &str| Some(path.trim()).filter(|path| !path.is_empty());
+            form.status = match TuningFiles::load(path(&form.scl_path), path(&form.kbm_path)) {
+                Ok(files) => {
+                    set_tuning(params, files);
+                    "loaded".to_string()
+                }
+                Err(err) => err,
+            };
+        }
+        if ui.button("12-TET").clicked() {
+            set_tuning(params, TuningFiles::default());
+            form.status = "12-TET".to_string();
+        }
+        ui.label(&form.status);
+    });
+}
+
+/// Stores the files in the plugin state and hands their tuning to the audio thread, parsed here
+/// so it doesn't have to allocate.
+fn set_tuning(params: &SynthParams, files: TuningFiles) {
+    match Tuning::from_files(&files) {
+        Ok(tuning) => {
+            *params.tuning.write().unwrap() = files;
+            *params.pending_tuning.lock().unwrap() = Some(tuning);
+        }
+        Err(err) => nih_error!("invalid tuning: {err}"),
+    }
+}
@@ -0,0 +1,303 @@
+use crate::{
+    additive_engine::MAX_HARMONICS,
+    demodulator::{frame_harmonic, frame_size, DEFAULT_FRAME_RATE, SYNC_LEVEL},
+    DistributionMode,
+};
+
+/// Inverse of [`crate::demodulator::CVDemodulator`]: lays a spectrum out as CV frames.
+///
+/// Decoding a frame gives back the submitted spectrum only within a few limits:
+/// - a frame carries partials `offset.max(1)..offset + count`, so partial `offset + count` and
+///   everything above it decode to silence,
+/// - partials that share a sample are encoded as their mean, and decode to it,
+/// - amplitudes are clamped to what `floor..=ceiling` can represent,
+/// - levels are written through the signed-square curve, so only that transfer curve inverts
+///   them.
+pub struct CVEncoder {
+    frame_size: usize,
+    progress: usize,
+    prev_harmonic: usize,
+    next_amp_l: [f32; MAX_HARMONICS],
+    next_amp_r: [f32; MAX_HARMONICS],
+    amp_l: [f32; MAX_HARMONICS],
+    amp_r: [f32; MAX_HARMONICS],
+}
+
+impl Default for CVEncoder {
+    fn default() -> Self {
+        Self {
+            frame_size: frame_size(44100.0, DEFAULT_FRAME_RATE),
+            progress: 0,
+            prev_harmonic: 0,
+            next_amp_l: [0.0; MAX_HARMONICS],
+            next_amp_r: [0.0; MAX_HARMONICS],
+            amp_l: [0.0; MAX_HARMONICS],
+            amp_r: [0.0; MAX_HARMONICS],
+        }
+    }
+}
+
+/// Maps an amplitude back through the signed-square curve into a CV sample.
+///
+/// Amplitudes outside of `floor..=ceiling` are clamped to the nearest representable level, and
+/// silence is written out of range when 0 itself cannot be represented.
+fn encode_level(amp: f32, floor: f32, ceiling: f32, bias: f32) -> f32 {
+    let level = amp.abs().sqrt() * amp.signum();
+
+    let level = if floor > ceiling {
+        ceiling + 1.0
+    } else if amp == 0.0 {
+        if floor > 0.0 {
+            floor - 1.0
+        } else if ceiling < 0.0 {
+            ceiling + 1.0
+        } else {
+            0.0
+        }
+    } else {
+        level.clamp(floor, ceiling)
+    };
+
+    level - bias
+}
+
+impl CVEncoder {
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    pub fn set_frame_size(&mut self, frame_size: usize) {
+        if frame_size != self.frame_size {
+            self.frame_size = frame_size;
+            self.reset();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.progress = 0;
+        self.prev_harmonic = 0;
+    }
+
+    /// Sets the spectrum to encode, picked up at the start of the next frame.
+    pub fn submit_amplitudes(&mut self, amp_l: &[f32], amp_r: &[f32]) {
+        self.next_amp_l.copy_from_slice(amp_l);
+        self.next_amp_r.copy_from_slice(amp_r);
+    }
+
+    /// The amplitudes carried by the sample at the current frame position.
+    fn next_amplitudes(
+        &mut self,
+        distribution_mode: &DistributionMode,
+        harmonic_count: usize,
+        harmonic_offset: usize,
+    ) -> (f32, f32) {
+        let next_harmonic = frame_harmonic(
+            self.progress,
+            self.frame_size,
+            distribution_mode,
+            harmonic_count,
+            harmonic_offset,
+        );
+        let first_harmonic = (self.prev_harmonic + 1).max(harmonic_offset).max(1);
+
+        if next_harmonic >= first_harmonic {
+            // partials sharing a sample can only be carried as their mean
+            let mut l = 0.0;
+            let mut r = 0.0;
+            for harmonic in first_harmonic..=next_harmonic {
+                l += self.amp_l[harmonic - 1];
+                r += self.amp_r[harmonic - 1];
+            }
+            let n = (next_harmonic - first_harmonic + 1) as f32;
+            self.prev_harmonic = next_harmonic;
+            (l / n, r / n)
+        } else if self.prev_harmonic > 0 {
+            (
+                self.amp_l[self.prev_harmonic - 1],
+                self.amp_r[self.prev_harmonic - 1],
+            )
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_samples(
+        &mut self,
+        out_l: &mut [f32],
+        out_r: &mut [f32],
+        distribution_mode: &DistributionMode,
+        harmonic_count: usize,
+        harmonic_offset: usize,
+        floor: f32,
+        ceiling: f32,
+        bias: f32,
+        sync: bool,
+    ) {
+        assert_eq!(
+            out_l.len(),
+            out_r.len(),
+            "channel output buffers must match length"
+        );
+
+        for (out_l, out_r) in out_l.iter_mut().zip(out_r.iter_mut()) {
+            if self.progress == 0 {
+                self.amp_l.copy_from_slice(&self.next_amp_l);
+                self.amp_r.copy_from_slice(&self.next_amp_r);
+                self.prev_harmonic = 0;
+            }
+
+            if sync && self.progress == 0 {
+                *out_l = SYNC_LEVEL;
+                *out_r = SYNC_LEVEL;
+            } else {
+                let (l, r) =
+                    self.next_amplitudes(distribution_mode, harmonic_count, harmonic_offset);
+                *out_l = encode_level(l, floor, ceiling, bias);
+                *out_r = encode_level(r, floor, ceiling, bias);
+            }
+
+            self.progress += 1;
+            if self.progress >= self.frame_size {
+                self.progress = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        demodulator::CVDemodulator,
+        transfer_curve::{Transfer, TRANSFER_TABLE_POINTS},
+        CvLayout, TransferCurve,
+    };
+
+    const FRAME_SIZE: usize = 64;
+
+    /// Encodes one frame of `amps` and decodes it again with the matching settings.
+    fn round_trip(
+        amps: &[f32; MAX_HARMONICS],
+        distribution_mode: &DistributionMode,
+        harmonic_count: usize,
+        harmonic_offset: usize,
+        sync: bool,
+    ) -> [f32; MAX_HARMONICS] {
+        let (floor, ceiling, bias) = (-1.0, 1.0, 0.0);
+
+        let mut encoder = CVEncoder::default();
+        encoder.set_frame_size(FRAME_SIZE);
+        encoder.submit_amplitudes(amps, amps);
+        let mut out_l = [0.0; FRAME_SIZE];
+        let mut out_r = [0.0; FRAME_SIZE];
+        encoder.generate_samples(
+            &mut out_l,
+            &mut out_r,
+            distribution_mode,
+            harmonic_count,
+            harmonic_offset,
+            floor,
+            ceiling,
+            bias,
+            sync,
+        );
+
+        let mut demodulator = CVDemodulator::default();
+        demodulator.set_frame_size(FRAME_SIZE);
+        let transfer = Transfer {
+            floor,
+            ceiling,
+            bias,
+            curve: &TransferCurve::SignedSquare,
+            table: &[0.0; TRANSFER_TABLE_POINTS],
+            decibel_min: -96.0,
+            decibel_max: 0.0,
+        };
+        let (l, r) = demodulator
+            .submit_samples(
+                &out_l,
+                &out_r,
+                distribution_mode,
+                harmonic_count,
+                harmonic_offset,
+                &transfer,
+                &CvLayout::Stereo,
+                sync,
+            )
+            .expect("a whole frame was submitted");
+        assert_eq!(l, r);
+        l
+    }
+
+    /// A falling spectrum over the (1-indexed) partials `first..=last`, alternating in sign.
+    fn spectrum(first: usize, last: usize) -> [f32; MAX_HARMONICS] {
+        let mut amps = [0.0; MAX_HARMONICS];
+        let len = (last - first + 1) as f32;
+        for (i, amp) in amps[first - 1..last].iter_mut().enumerate() {
+            *amp = (1.0 - i as f32 / len) * if i % 2 == 0 { 1.0 } else { -0.5 };
+        }
+        amps
+    }
+
+    fn assert_close(decoded: &[f32], expected: &[f32]) {
+        for (i, (decoded, expected)) in decoded.iter().zip(expected).enumerate() {
+            assert!(
+                (decoded - expected).abs() < 1e-5,
+                "partial {}: decoded {decoded}, expected {expected}",
+                i + 1
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_partials_with_their_own_samples() {
+        // mode, count, offset, sync, and the partials the frame reaches
+        let cases = [
+            (DistributionMode::Linear, 32, 0, false, 1..=31),
+            (DistributionMode::Linear, 64, 0, false, 1..=63),
+            (DistributionMode::Linear, 16, 8, true, 8..=23),
+            (DistributionMode::Exponential, 8, 0, false, 1..=7),
+            (DistributionMode::Exponential, 8, 0, true, 1..=7),
+        ];
+        for (mode, count, offset, sync, partials) in cases {
+            let amps = spectrum(*partials.start(), *partials.end());
+            let decoded = round_trip(&amps, &mode, count, offset, sync);
+            assert_close(&decoded, &amps);
+        }
+    }
+
+    #[test]
+    fn stops_short_of_count_plus_offset() {
+        for (count, offset) in [(32, 0), (16, 8)] {
+            let amps = spectrum(offset.max(1), count + offset + 4);
+            let decoded = round_trip(&amps, &DistributionMode::Linear, count, offset, false);
+
+            let mut expected = amps;
+            expected[count + offset - 1..].fill(0.0);
+            assert_close(&decoded, &expected);
+        }
+    }
+
+    #[test]
+    fn shared_samples_decode_to_the_mean() {
+        let amps = spectrum(1, 126);
+        let decoded = round_trip(&amps, &DistributionMode::Linear, 128, 0, false);
+
+        // every sample after the first carries two partials
+        let mut expected = amps;
+        for pair in expected[..126].chunks_mut(2) {
+            let mean = (pair[0] + pair[1]) / 2.0;
+            pair.fill(mean);
+        }
+        assert_close(&decoded, &expected);
+    }
+
+    #[test]
+    fn clamps_to_the_ceiling() {
+        let mut amps = [0.0; MAX_HARMONICS];
+        amps[..4].copy_from_slice(&[4.0, -4.0, 0.25, 0.0]);
+        let decoded = round_trip(&amps, &DistributionMode::Linear, 5, 0, false);
+        assert_close(&decoded[..4], &[1.0, -1.0, 0.25, 0.0]);
+    }
+}
@@ -1,50 +1,276 @@
+use crate::EnvelopeCurve;
 use nih_plug::nih_debug_assert;
 
+/// How strongly the exponential and logarithmic curves bend.
+const CURVE_STEEPNESS: f32 = 5.0;
+
+#[derive(Debug, Default, PartialEq)]
+enum Stage {
+    #[default]
+    Idle,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Attack, hold, decay, sustain and release, each ramping stage taking its own curve. Every stage
+/// starts from wherever the previous one left off, so retriggers and early releases don't jump.
 #[derive(Debug, Default)]
-pub struct AREnvelope {
+pub struct ADSREnvelope {
+    stage: Stage,
     state: f32,
+    /// Level at the start of the current stage, and how far through it the envelope is.
+    stage_start: f32,
+    progress: f32,
 
-    attack_coeff: f32,
-    release_coeff: f32,
+    attack_step: f32,
+    hold_step: f32,
+    decay_step: f32,
+    release_step: f32,
+    sustain_level: f32,
 
-    releasing: bool,
+    attack_steepness: f32,
+    decay_steepness: f32,
+    release_steepness: f32,
 }
 
-impl AREnvelope {
+/// Progress per sample for a stage lasting `time_ms`. Stages shorter than a sample finish at once.
+fn stage_step(sample_rate: f32, time_ms: f32) -> f32 {
+    let samples = time_ms / 1000.0 * sample_rate;
+    if samples < 1.0 {
+        1.0
+    } else {
+        1.0 / samples
+    }
+}
+
+/// Exponential curves cover most of the distance early on, like a charging capacitor, and
+/// logarithmic curves late.
+fn curve_steepness(curve: &EnvelopeCurve) -> f32 {
+    match curve {
+        EnvelopeCurve::Linear => 0.0,
+        EnvelopeCurve::Exponential => CURVE_STEEPNESS,
+        EnvelopeCurve::Logarithmic => -CURVE_STEEPNESS,
+    }
+}
+
+fn shape(progress: f32, steepness: f32) -> f32 {
+    if steepness == 0.0 {
+        progress
+    } else {
+        (1.0 - (-steepness * progress).exp()) / (1.0 - (-steepness).exp())
+    }
+}
+
+impl ADSREnvelope {
     pub fn set_attack_time(&mut self, sample_rate: f32, time_ms: f32) {
-        self.attack_coeff = (-1.0 / (time_ms / 1000.0 * sample_rate)).exp();
+        self.attack_step = stage_step(sample_rate, time_ms);
+    }
+
+    pub fn set_hold_time(&mut self, sample_rate: f32, time_ms: f32) {
+        self.hold_step = stage_step(sample_rate, time_ms);
+    }
+
+    pub fn set_decay_time(&mut self, sample_rate: f32, time_ms: f32) {
+        self.decay_step = stage_step(sample_rate, time_ms);
     }
 
     pub fn set_release_time(&mut self, sample_rate: f32, time_ms: f32) {
-        self.release_coeff = (-1.0 / (time_ms / 1000.0 * sample_rate)).exp();
+        self.release_step = stage_step(sample_rate, time_ms);
+    }
+
+    pub fn set_sustain_level(&mut self, level: f32) {
+        self.sustain_level = level;
+    }
+
+    pub fn set_curves(
+        &mut self,
+        attack: &EnvelopeCurve,
+        decay: &EnvelopeCurve,
+        release: &EnvelopeCurve,
+    ) {
+        self.attack_steepness = curve_steepness(attack);
+        self.decay_steepness = curve_steepness(decay);
+        self.release_steepness = curve_steepness(release);
     }
 
     pub fn reset(&mut self) {
         self.state = 0.0;
-        self.releasing = false;
+        self.enter(Stage::Idle);
+    }
+
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.stage_start = self.state;
+        self.progress = 0.0;
+    }
+
+    /// Moves the current stage towards `target`, returning `true` once it is complete.
+    fn ramp(&mut self, step: f32, target: f32, steepness: f32) -> bool {
+        self.progress = (self.progress + step).min(1.0);
+        self.state =
+            self.stage_start + (target - self.stage_start) * shape(self.progress, steepness);
+        self.progress >= 1.0
     }
 
     pub fn next_block(&mut self, block_values: &mut [f32], block_len: usize) {
         nih_debug_assert!(block_values.len() >= block_len);
         for value in block_values.iter_mut().take(block_len) {
-            let (target, t) = if self.releasing {
-                (0.0, self.release_coeff)
-            } else {
-                (1.0, self.attack_coeff)
-            };
-
-            let new = (self.state * t) + (target * (1.0 - t));
-            self.state = new;
+            match self.stage {
+                Stage::Idle => self.state = 0.0,
+                Stage::Attack => {
+                    if self.ramp(self.attack_step, 1.0, self.attack_steepness) {
+                        self.enter(Stage::Hold);
+                    }
+                }
+                Stage::Hold => {
+                    self.progress += self.hold_step;
+                    if self.progress >= 1.0 {
+                        self.enter(Stage::Decay);
+                    }
+                }
+                Stage::Decay => {
+                    if self.ramp(self.decay_step, self.sustain_level, self.decay_steepness) {
+                        // there's nothing left to sustain or release at a level of 0
+                        self.enter(if self.sustain_level <= 0.0 {
+                            Stage::Idle
+                        } else {
+                            Stage::Sustain
+                        });
+                    }
+                }
+                Stage::Sustain => self.state = self.sustain_level,
+                Stage::Release => {
+                    if self.ramp(self.release_step, 0.0, self.release_steepness) {
+                        self.enter(Stage::Idle);
+                    }
+                }
+            }
 
-            *value = new;
+            *value = self.state;
         }
     }
 
+    /// Enters the attack stage from the current level.
+    pub fn retrigger(&mut self) {
+        self.enter(Stage::Attack);
+    }
+
+    pub fn value(&self) -> f32 {
+        self.state
+    }
+
     pub fn start_release(&mut self) {
-        self.releasing = true;
+        self.enter(Stage::Release);
     }
 
     pub fn is_releasing(&self) -> bool {
-        self.releasing && self.state >= 0.001
+        self.stage == Stage::Release
+    }
+
+    /// Whether the envelope has finished, or never started.
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sample per millisecond, so stage times are in samples.
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn envelope(curve: &EnvelopeCurve, sustain_level: f32) -> ADSREnvelope {
+        let mut envelope = ADSREnvelope::default();
+        envelope.set_attack_time(SAMPLE_RATE, 10.0);
+        envelope.set_hold_time(SAMPLE_RATE, 5.0);
+        envelope.set_decay_time(SAMPLE_RATE, 10.0);
+        envelope.set_release_time(SAMPLE_RATE, 20.0);
+        envelope.set_sustain_level(sustain_level);
+        envelope.set_curves(curve, curve, curve);
+        envelope
+    }
+
+    /// Runs the envelope for `samples` samples, returning the last value.
+    fn run(envelope: &mut ADSREnvelope, samples: usize) -> f32 {
+        let mut values = vec![0.0; samples];
+        envelope.next_block(&mut values, samples);
+        values[samples - 1]
+    }
+
+    #[test]
+    fn runs_through_every_stage_to_sustain() {
+        let mut envelope = envelope(&EnvelopeCurve::Linear, 0.5);
+        assert_eq!(envelope.stage, Stage::Idle);
+        assert_eq!(run(&mut envelope, 4), 0.0);
+
+        envelope.retrigger();
+        assert_eq!(envelope.stage, Stage::Attack);
+        assert_eq!(run(&mut envelope, 10), 1.0);
+        assert_eq!(envelope.stage, Stage::Hold);
+        assert_eq!(run(&mut envelope, 5), 1.0);
+        assert_eq!(envelope.stage, Stage::Decay);
+        assert_eq!(run(&mut envelope, 10), 0.5);
+        assert_eq!(envelope.stage, Stage::Sustain);
+        assert_eq!(run(&mut envelope, 100), 0.5);
+    }
+
+    #[test]
+    fn releases_from_the_current_level() {
+        let mut envelope = envelope(&EnvelopeCurve::Linear, 0.5);
+        envelope.retrigger();
+        let level = run(&mut envelope, 5);
+        assert!((level - 0.5).abs() < 1e-6);
+
+        envelope.start_release();
+        // a twentieth of the way down from where the attack left off
+        assert!((run(&mut envelope, 1) - level * 0.95).abs() < 1e-6);
+        assert!(envelope.is_releasing());
+        assert_eq!(run(&mut envelope, 19), 0.0);
+        assert!(envelope.is_idle());
+    }
+
+    #[test]
+    fn retriggers_during_the_release() {
+        let mut envelope = envelope(&EnvelopeCurve::Linear, 0.5);
+        envelope.retrigger();
+        run(&mut envelope, 30);
+        envelope.start_release();
+        let level = run(&mut envelope, 10);
+
+        envelope.retrigger();
+        assert!(!envelope.is_releasing());
+        // the attack picks up from the released level rather than from 0
+        assert!((run(&mut envelope, 1) - (level + (1.0 - level) * 0.1)).abs() < 1e-6);
+        assert_eq!(run(&mut envelope, 9), 1.0);
+    }
+
+    #[test]
+    fn decaying_to_no_sustain_ends_the_envelope() {
+        let mut envelope = envelope(&EnvelopeCurve::Linear, 0.0);
+        envelope.retrigger();
+        assert_eq!(run(&mut envelope, 25), 0.0);
+        assert!(envelope.is_idle());
+        assert!(!envelope.is_releasing());
+    }
+
+    #[test]
+    fn every_curve_reaches_its_stage_targets() {
+        for curve in [
+            EnvelopeCurve::Linear,
+            EnvelopeCurve::Exponential,
+            EnvelopeCurve::Logarithmic,
+        ] {
+            let mut envelope = envelope(&curve, 0.25);
+            envelope.retrigger();
+            assert_eq!(run(&mut envelope, 1), shape(0.1, curve_steepness(&curve)));
+            assert_eq!(run(&mut envelope, 9), 1.0, "{curve:?} attack");
+            assert_eq!(run(&mut envelope, 15), 0.25, "{curve:?} decay");
+            envelope.start_release();
+            assert_eq!(run(&mut envelope, 20), 0.0, "{curve:?} release");
+        }
     }
 }
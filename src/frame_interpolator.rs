@@ -0,0 +1,180 @@
+use crate::{additive_engine::MAX_HARMONICS, FrameInterpolation};
+use std::f32::consts::PI;
+
+/// Frames kept for interpolation: the two frames being interpolated between, plus the one before
+/// them for the cubic mode's starting slope.
+const HISTORY: usize = 3;
+
+/// Spreads demodulated frames across the following frame, so each partial moves smoothly from one
+/// frame's amplitude to the next instead of stepping at the frame rate. Interpolating towards the
+/// newest frame means it is only reached a frame after it was decoded.
+pub struct FrameInterpolator {
+    frame_size: usize,
+    progress: usize,
+    frames_l: [[f32; MAX_HARMONICS]; HISTORY],
+    frames_r: [[f32; MAX_HARMONICS]; HISTORY],
+    amp_l: [f32; MAX_HARMONICS],
+    amp_r: [f32; MAX_HARMONICS],
+}
+
+impl Default for FrameInterpolator {
+    fn default() -> Self {
+        Self {
+            frame_size: 1,
+            progress: 0,
+            frames_l: [[0.0; MAX_HARMONICS]; HISTORY],
+            frames_r: [[0.0; MAX_HARMONICS]; HISTORY],
+            amp_l: [0.0; MAX_HARMONICS],
+            amp_r: [0.0; MAX_HARMONICS],
+        }
+    }
+}
+
+impl FrameInterpolator {
+    pub fn set_frame_size(&mut self, frame_size: usize) {
+        self.frame_size = frame_size.max(1);
+    }
+
+    pub fn reset(&mut self) {
+        self.progress = 0;
+        for frame in self.frames_l.iter_mut().chain(self.frames_r.iter_mut()) {
+            frame.fill(0.0);
+        }
+    }
+
+    pub fn push_frame(&mut self, amp_l: &[f32], amp_r: &[f32]) {
+        self.frames_l.rotate_left(1);
+        self.frames_r.rotate_left(1);
+        self.frames_l[HISTORY - 1].copy_from_slice(amp_l);
+        self.frames_r[HISTORY - 1].copy_from_slice(amp_r);
+        self.progress = 0;
+    }
+
+    /// Whether the newest frame has been reached, a whole frame after it was pushed.
+    pub fn frame_reached(&self) -> bool {
+        self.progress >= self.frame_size
+    }
+
+    /// Moves `samples` further into the current frame and returns the amplitudes there, for the
+    /// first `partials` partials only. Once a whole frame has passed without a new one, the
+    /// newest frame is held.
+    #[allow(clippy::needless_range_loop)]
+    pub fn advance(
+        &mut self,
+        samples: usize,
+        interpolation: &FrameInterpolation,
+        partials: usize,
+    ) -> (&[f32; MAX_HARMONICS], &[f32; MAX_HARMONICS]) {
+        self.progress = (self.progress + samples).min(self.frame_size);
+        let weights = Weights::new(interpolation, self.progress as f32 / self.frame_size as f32);
+
+        let [prev_l, from_l, to_l] = &self.frames_l;
+        let [prev_r, from_r, to_r] = &self.frames_r;
+        for i in 0..partials.min(MAX_HARMONICS) {
+            self.amp_l[i] = weights.interpolate(prev_l[i], from_l[i], to_l[i]);
+            self.amp_r[i] = weights.interpolate(prev_r[i], from_r[i], to_r[i]);
+        }
+
+        (&self.amp_l, &self.amp_r)
+    }
+}
+
+/// An interpolation curve evaluated at one point in the frame, shared by every partial.
+enum Weights {
+    Step,
+    /// How far to go from `from` to `to`.
+    Blend(f32),
+    /// Cubic Hermite basis functions, for `from`, its slope, `to` and its slope.
+    Hermite([f32; 4]),
+}
+
+impl Weights {
+    fn new(interpolation: &FrameInterpolation, t: f32) -> Self {
+        match interpolation {
+            FrameInterpolation::Off => Self::Step,
+            FrameInterpolation::Linear => Self::Blend(t),
+            FrameInterpolation::Cosine => Self::Blend((1.0 - f32::cos(t * PI)) * 0.5),
+            FrameInterpolation::Cubic => {
+                let t2 = t * t;
+                let t3 = t2 * t;
+                Self::Hermite([
+                    2.0 * t3 - 3.0 * t2 + 1.0,
+                    t3 - 2.0 * t2 + t,
+                    -2.0 * t3 + 3.0 * t2,
+                    t3 - t2,
+                ])
+            }
+        }
+    }
+
+    fn interpolate(&self, prev: f32, from: f32, to: f32) -> f32 {
+        match *self {
+            Self::Step => to,
+            Self::Blend(weight) => from + (to - from) * weight,
+            Self::Hermite([h_from, h_slope_from, h_to, h_slope_to]) => {
+                // with no frame after `to` yet, its slope is taken from behind it
+                let slope_from = (to - prev) * 0.5;
+                let slope_to = to - from;
+                h_from * from + h_slope_from * slope_from + h_to * to + h_slope_to * slope_to
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_SIZE: usize = 8;
+
+    /// An interpolator that has just received a frame of 1s after one of 0.5s.
+    fn interpolator() -> FrameInterpolator {
+        let mut interpolator = FrameInterpolator::default();
+        interpolator.set_frame_size(FRAME_SIZE);
+        interpolator.push_frame(&[0.5; MAX_HARMONICS], &[-0.5; MAX_HARMONICS]);
+        interpolator.push_frame(&[1.0; MAX_HARMONICS], &[-1.0; MAX_HARMONICS]);
+        interpolator
+    }
+
+    #[test]
+    fn curves_run_from_the_previous_frame_to_the_newest() {
+        for interpolation in [
+            FrameInterpolation::Linear,
+            FrameInterpolation::Cosine,
+            FrameInterpolation::Cubic,
+        ] {
+            let mut interpolator = interpolator();
+            let (amp_l, amp_r) = interpolator.advance(0, &interpolation, 1);
+            assert_eq!(
+                (amp_l[0], amp_r[0]),
+                (0.5, -0.5),
+                "{interpolation:?} at t = 0"
+            );
+
+            let (amp_l, amp_r) = interpolator.advance(FRAME_SIZE / 2, &interpolation, 1);
+            assert!(
+                amp_l[0] > 0.5 && amp_l[0] < 1.0,
+                "{interpolation:?} halfway"
+            );
+            assert!(
+                amp_r[0] < -0.5 && amp_r[0] > -1.0,
+                "{interpolation:?} halfway"
+            );
+
+            let (amp_l, amp_r) = interpolator.advance(FRAME_SIZE, &interpolation, 1);
+            assert_eq!(
+                (amp_l[0], amp_r[0]),
+                (1.0, -1.0),
+                "{interpolation:?} at t = 1"
+            );
+            assert!(interpolator.frame_reached());
+        }
+    }
+
+    #[test]
+    fn off_steps_straight_to_the_newest_frame() {
+        let mut interpolator = interpolator();
+        let (amp_l, amp_r) = interpolator.advance(0, &FrameInterpolation::Off, 1);
+        assert_eq!((amp_l[0], amp_r[0]), (1.0, -1.0));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{additive_engine::MAX_HARMONICS, slew_limiter::SlewLimiter};
+use nih_plug::nih_debug_assert_failure;
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner};
+use std::{
+    f64::consts::PI,
+    sync::{Arc, OnceLock},
+};
+
+pub const FFT_SIZE: usize = 1024;
+const HOP_SIZE: usize = FFT_SIZE / 2;
+/// Bins either side of a partial that its window kernel is spread across. Hann sidelobes fall
+/// off fast enough that truncating here keeps the error around 70 dB below the output.
+const KERNEL_RADIUS: isize = 16;
+const KERNEL_LEN: usize = 2 * KERNEL_RADIUS as usize + 1;
+
+/// Overlap-add inverse-FFT resynthesis (FFT⁻¹). Each hop, every partial's Hann-windowed sinusoid
+/// is written into a spectrum as a shifted window kernel, and one inverse FFT per channel
+/// synthesizes the whole frame. Hann frames at half overlap sum to unity, so stationary spectra
+/// come out the same as the oscillator bank.
+pub struct IfftResynth {
+    ifft: Arc<dyn ComplexToReal<f32>>,
+    spectrum_l: Vec<Complex<f32>>,
+    spectrum_r: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    frame: Vec<f32>,
+    overlap_l: Vec<f32>,
+    overlap_r: Vec<f32>,
+    hop_progress: usize,
+    dirty: bool,
+}
+
+/// The inverse FFT plan. Every instance runs the same size, so it is planned once and shared.
+fn inverse_fft() -> Arc<dyn ComplexToReal<f32>> {
+    static IFFT: OnceLock<Arc<dyn ComplexToReal<f32>>> = OnceLock::new();
+    IFFT.get_or_init(|| RealFftPlanner::<f32>::new().plan_fft_inverse(FFT_SIZE))
+        .clone()
+}
+
+impl Default for IfftResynth {
+    fn default() -> Self {
+        let ifft = inverse_fft();
+
+        Self {
+            spectrum_l: ifft.make_input_vec(),
+            spectrum_r: ifft.make_input_vec(),
+            scratch: ifft.make_scratch_vec(),
+            frame: ifft.make_output_vec(),
+            overlap_l: vec![0.0; FFT_SIZE],
+            overlap_r: vec![0.0; FFT_SIZE],
+            hop_progress: 0,
+            dirty: false,
+            ifft,
+        }
+    }
+}
+
+/// `sin(πx / FFT_SIZE)`, for the small arguments the kernel needs.
+fn kernel_sin(x: f64) -> f64 {
+    let x = PI * x / FFT_SIZE as f64;
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0)))
+}
+
+impl IfftResynth {
+    pub fn reset(&mut self) {
+        if self.dirty {
+            self.overlap_l.fill(0.0);
+            self.overlap_r.fill(0.0);
+            self.hop_progress = 0;
+            self.dirty = false;
+        }
+    }
+
+    /// Adds a contribution at (possibly out of range) bin `m` of the full spectrum, folding it
+    /// into the half spectrum the real inverse FFT works on.
+    fn add_bin(spectrum: &mut [Complex<f32>], m: isize, c: Complex<f32>) {
+        let half = (FFT_SIZE / 2) as isize;
+        if m == 0 || m == half {
+            spectrum[m as usize].re += 2.0 * c.re;
+        } else if m > 0 && m < half {
+            spectrum[m as usize] += c;
+        } else if m < 0 && m > -half {
+            spectrum[(-m) as usize] += c.conj();
+        } else if m > half && m < 2 * half {
+            spectrum[(2 * half - m) as usize] += c.conj();
+        }
+    }
+
+    #[allow(clippy::too_many_arguments, clippy::needless_range_loop)]
+    fn synthesize_frame(
+        &mut self,
+        phases: &mut [f64; MAX_HARMONICS],
+        amp_l: &[f32; MAX_HARMONICS],
+        amp_r: &[f32; MAX_HARMONICS],
+        last_amp_l: &mut [f32; MAX_HARMONICS],
+        last_amp_r: &mut [f32; MAX_HARMONICS],
+        i_freqs: &[f64; MAX_HARMONICS],
+        sample_rate: f32,
+        gains: &[f32; MAX_HARMONICS],
+        partials: usize,
+        slew: &SlewLimiter,
+    ) {
+        let n = FFT_SIZE as f64;
+        let sr_f64 = sample_rate as f64;
+
+        self.spectrum_l.fill(Complex::default());
+        self.spectrum_r.fill(Complex::default());
+
+        // e^{iπ/N}, the per-bin phase rotation of the kernel
+        let (rot_im, rot_re) = (PI / n).sin_cos();
+        let rot = Complex::new(rot_re, rot_im);
+
+        for i in 0..partials {
+            let freq = i_freqs[i];
+            let step = freq / sr_f64;
+            let phase = phases[i] + step;
+
+            phases[i] = (phases[i] + step * HOP_SIZE as f64) % 2.0;
+
+            if freq < 20.0 || freq > sr_f64 / 2.0 {
+                continue;
+            }
+
+            let amp_l = slew.next(i, last_amp_l[i], amp_l[i], HOP_SIZE);
+            let amp_r = slew.next(i, last_amp_r[i], amp_r[i], HOP_SIZE);
+
+            last_amp_l[i] = amp_l;
+            last_amp_r[i] = amp_r;
+
+            if amp_l == 0.0 && amp_r == 0.0 {
+                continue;
+            }
+
+            let gain = gains[i] as f64;
+
+            // the kernel is singular on exact bin centres, so nudge off of them
+            let mut bin = freq * n / sr_f64;
+            if (bin - bin.round()).abs() < 1e-9 {
+                bin += 1e-6;
+            }
+
+            let first_bin = bin.round() as isize - KERNEL_RADIUS;
+            let delta = bin - first_bin as f64;
+
+            // a·sin(2πp) = a·cos(2πp - π/2), split into positive and negative frequencies, with
+            // the inverse FFT's 1/N folded in
+            let (sin_phi, cos_phi) = (phase * std::f64::consts::TAU - PI / 2.0).sin_cos();
+            let (sin_theta, cos_theta) = (PI * delta * (n - 1.0) / n).sin_cos();
+            let scale = gain / (2.0 * n) * (PI * delta).sin();
+            let mut c = Complex::new(cos_phi, sin_phi) * Complex::new(cos_theta, sin_theta) * scale;
+
+            let mut s_prev = kernel_sin(delta + 1.0);
+            let mut s = kernel_sin(delta);
+            for j in 0..KERNEL_LEN {
+                let s_next = kernel_sin(delta - j as f64 - 1.0);
+                let w = Complex::new(0.5 / s, 0.0)
+                    - rot.conj() * (0.25 / s_prev)
+                    - rot * (0.25 / s_next);
+                let k = c * w;
+                let k = Complex::new(k.re as f32, k.im as f32);
+
+                let m = first_bin + j as isize;
+                Self::add_bin(&mut self.spectrum_l, m, k * amp_l);
+                Self::add_bin(&mut self.spectrum_r, m, k * amp_r);
+
+                c *= rot;
+                s_prev = s;
+                s = s_next;
+            }
+        }
+
+        // the real inverse FFT only fails on non-zero DC/Nyquist imaginary parts, which
+        // `add_bin` never writes, but should it happen the frame is left silent
+        for (spectrum, overlap) in [
+            (&mut self.spectrum_l, &mut self.overlap_l),
+            (&mut self.spectrum_r, &mut self.overlap_r),
+        ] {
+            if let Err(err) =
+                self.ifft
+                    .process_with_scratch(spectrum, &mut self.frame, &mut self.scratch)
+            {
+                nih_debug_assert_failure!("inverse FFT failed: {}", err);
+                continue;
+            }
+            for (o, s) in overlap.iter_mut().zip(self.frame.iter()) {
+                *o += *s;
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_samples(
+        &mut self,
+        phases: &mut [f64; MAX_HARMONICS],
+        amp_l: &[f32; MAX_HARMONICS],
+        amp_r: &[f32; MAX_HARMONICS],
+        last_amp_l: &mut [f32; MAX_HARMONICS],
+        last_amp_r: &mut [f32; MAX_HARMONICS],
+        i_freqs: &[f64; MAX_HARMONICS],
+        sample_rate: f32,
+        out_l: &mut [f32],
+        out_r: &mut [f32],
+        gains: &[f32; MAX_HARMONICS],
+        partials: usize,
+        slew: &SlewLimiter,
+    ) {
+        self.dirty = true;
+
+        for (out_l, out_r) in out_l.iter_mut().zip(out_r.iter_mut()) {
+            if self.hop_progress == 0 {
+                self.overlap_l.copy_within(HOP_SIZE.., 0);
+                self.overlap_r.copy_within(HOP_SIZE.., 0);
+                self.overlap_l[HOP_SIZE..].fill(0.0);
+                self.overlap_r[HOP_SIZE..].fill(0.0);
+
+                self.synthesize_frame(
+                    phases,
+                    amp_l,
+                    amp_r,
+                    last_amp_l,
+                    last_amp_r,
+                    i_freqs,
+                    sample_rate,
+                    gains,
+                    partials,
+                    slew,
+                );
+            }
+
+            *out_l += self.overlap_l[self.hop_progress];
+            *out_r += self.overlap_r[self.hop_progress];
+
+            self.hop_progress += 1;
+            if self.hop_progress >= HOP_SIZE {
+                self.hop_progress = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{additive_engine::AdditiveEngine, BasicGainMode, SlewMode, SynthesisBackend};
+
+    #[test]
+    fn matches_the_oscillator_bank_for_a_stationary_spectrum() {
+        let sample_rate = 48000.0;
+        let len = 8 * HOP_SIZE;
+        let i_freqs = std::array::from_fn(|i| 441.5 * (i + 1) as f64);
+        let mut amp_l = [0.0; MAX_HARMONICS];
+        let mut amp_r = [0.0; MAX_HARMONICS];
+        for (i, (l, r)) in amp_l.iter_mut().zip(&mut amp_r).take(16).enumerate() {
+            *l = 1.0 / (i + 1) as f32;
+            *r = -0.5 / (i + 1) as f32;
+        }
+
+        let mut slew = SlewLimiter::default();
+        slew.update(sample_rate, false, &SlewMode::Linear, 0.0, 0.0, 0.0);
+
+        let render = |backend: SynthesisBackend| {
+            let mut engine = AdditiveEngine::default();
+            engine.set_partial_budget(32);
+            engine.submit_amplitudes(&amp_l, &amp_r);
+            let mut out_l = vec![0.0; len];
+            let mut out_r = vec![0.0; len];
+            // in blocks that don't line up with the hops
+            for (block_l, block_r) in out_l.chunks_mut(100).zip(out_r.chunks_mut(100)) {
+                engine.generate_samples(
+                    &i_freqs,
+                    sample_rate,
+                    block_l,
+                    block_r,
+                    &BasicGainMode::Flat,
+                    &slew,
+                    &backend,
+                );
+            }
+            (out_l, out_r)
+        };
+        let (bank_l, bank_r) = render(SynthesisBackend::OscillatorBank);
+        let (ifft_l, ifft_r) = render(SynthesisBackend::InverseFft);
+
+        // the first hop only has half a window under it
+        for n in HOP_SIZE..len {
+            assert!(
+                (bank_l[n] - ifft_l[n]).abs() < 1e-3 && (bank_r[n] - ifft_r[n]).abs() < 1e-3,
+                "sample {n}: oscillator bank ({}, {}), inverse FFT ({}, {})",
+                bank_l[n],
+                bank_r[n],
+                ifft_l[n],
+                ifft_r[n]
+            );
+        }
+    }
+}
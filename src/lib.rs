@@ -1,18 +1,52 @@
-use demodulator::{CVDemodulator, DEMOD_BLOCK_SIZE};
+use additive_engine::MAX_HARMONICS;
+use demodulator::{DEFAULT_FRAME_RATE, MIN_FRAME_RATE};
+use modulator::ModulatorPlugin;
 use nih_plug::prelude::*;
-use std::{env, sync::Arc};
-use voice::AdditiveVoice;
-
-mod additive_engine;
-mod demodulator;
-mod envelope;
-mod voice;
-
-struct SynthPlugin {
+use nih_plug_egui::EguiState;
+use slew_limiter::DEFAULT_SLEW_MS;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use synth::{NoteEventIo, Synth, SynthSettings};
+use transfer_curve::TRANSFER_TABLE_POINTS;
+use tuning::{Tuning, TuningFiles, DEFAULT_REFERENCE_PITCH};
+use voice::{DEFAULT_BEND_RANGE, DEFAULT_MPE_BEND_RANGE, MAX_UNISON};
+use voice_pool::MAX_POLYPHONY;
+
+pub mod additive_engine;
+pub mod delay_line;
+pub mod demodulator;
+pub mod editor;
+pub mod encoder;
+pub mod envelope;
+pub mod frame_interpolator;
+pub mod ifft_resynth;
+pub mod modulator;
+pub mod slew_limiter;
+pub mod stereo;
+pub mod synth;
+pub mod transfer_curve;
+pub mod tuning;
+pub mod voice;
+pub mod voice_pool;
+
+// the parameters hosts can modulate per voice, each held by its voice while it plays
+pub const ATTACK_POLY_MOD_ID: u32 = 0;
+pub const RELEASE_POLY_MOD_ID: u32 = 1;
+pub const UNISON_DETUNE_POLY_MOD_ID: u32 = 2;
+pub const VELOCITY_TILT_POLY_MOD_ID: u32 = 3;
+pub const GLIDE_POLY_MOD_ID: u32 = 4;
+pub const HOLD_POLY_MOD_ID: u32 = 5;
+pub const DECAY_POLY_MOD_ID: u32 = 6;
+pub const SUSTAIN_POLY_MOD_ID: u32 = 7;
+
+pub struct SynthPlugin {
     params: Arc<SynthParams>,
-    voice: AdditiveVoice,
-    demodulator: CVDemodulator,
-    sample_rate: f32,
+    synth: Synth,
 }
 
 #[derive(Enum, PartialEq, Debug)]
@@ -27,40 +61,285 @@ pub enum BasicGainMode {
     Flat,
 }
 
+#[derive(Enum, PartialEq, Debug)]
+pub enum CvLayout {
+    /// Left and right channel amplitudes.
+    Stereo,
+    /// Amplitudes on the left channel, played on both sides, and phases on the right, where
+    /// -1..1 covers a full cycle.
+    AmplitudePhase,
+    /// Amplitudes on the left channel, played on both sides, and each partial's frequency as a
+    /// ratio to the fundamental on the right, where every 0.1 is an octave.
+    AmplitudeRatio,
+    /// The average of both channels' amplitudes, played on both sides.
+    #[name = "Mono (sum)"]
+    MonoSum,
+    /// Amplitudes on the left channel only, played on both sides.
+    #[name = "Mono (left)"]
+    MonoLeft,
+    /// Mid amplitudes on the left channel and side amplitudes on the right, each decoded before
+    /// being matrixed to left and right. Side levels beyond the mid are limited to it, so neither
+    /// side flips polarity.
+    #[name = "Mid/side"]
+    MidSide,
+    /// Amplitudes on both channels, played on both sides, with the right channel carrying the
+    /// partials after the left's, e.g. 257 to 512 for a partial count of 256. The partial count
+    /// is limited to half of the partials above the offset, so both channels fit.
+    #[name = "Dual resolution"]
+    DualResolution,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum CvSource {
+    /// CV on the main input, which is replaced by the synth rather than mixed at the dry level.
+    Main,
+    Sidechain,
+}
+
+/// How unison copies are spread across the detune range.
+#[derive(Enum, PartialEq, Debug)]
+pub enum DetuneCurve {
+    Linear,
+    /// Most copies close to the centre pitch, with the outermost at the full detune.
+    Clustered,
+    /// Most copies out towards the edges.
+    Wide,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum EnvelopeCurve {
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum ExpressionTarget {
+    Off,
+    Floor,
+    Ceiling,
+    Bias,
+    /// Spectral tilt per voice, where an amount of 1 is 6 dB per octave.
+    Brightness,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum FrameInterpolation {
+    Off,
+    Linear,
+    Cosine,
+    Cubic,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum GlideMode {
+    ConstantTime,
+    ConstantRate,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum PanMode {
+    Off,
+    /// Odd partials, starting from the fundamental, to the left and even ones to the right.
+    #[name = "Odd/even"]
+    OddEven,
+    /// Partials swept from left to right as they rise, over nine octaves.
+    Frequency,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum PhaseMode {
+    Snap,
+    Glide,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum SlewMode {
+    Linear,
+    OnePole,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum SynthesisBackend {
+    OscillatorBank,
+    InverseFft,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum TransferCurve {
+    Linear,
+    SignedSquare,
+    Cubic,
+    /// Maps `floor..=1` linearly to `decibel_min..=decibel_max`, with the floor as silence.
+    #[name = "Decibel (floor = silence, 1 = max)"]
+    Decibel,
+    Table,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum VoiceStealing {
+    Oldest,
+    Quietest,
+    SameNote,
+}
+
 #[derive(Params)]
-struct SynthParams {
+pub struct SynthParams {
+    #[id = "cv_source"]
+    cv_source: EnumParam<CvSource>,
+    #[id = "dry_level"]
+    dry_level: FloatParam,
+    #[id = "cv_layout"]
+    cv_layout: EnumParam<CvLayout>,
+    #[id = "phase_mode"]
+    phase_mode: EnumParam<PhaseMode>,
+    #[id = "frame_rate"]
+    frame_rate: FloatParam,
     #[id = "floor"]
     floor: FloatParam,
     #[id = "ceiling"]
     ceiling: FloatParam,
     #[id = "bias"]
     bias: FloatParam,
+    #[id = "transfer_curve"]
+    transfer_curve: EnumParam<TransferCurve>,
+    #[id = "decibel_min"]
+    decibel_min: FloatParam,
+    #[id = "decibel_max"]
+    decibel_max: FloatParam,
+    #[nested(array, group = "transfer table")]
+    transfer_table: [TransferPointParams; TRANSFER_TABLE_POINTS],
+    #[id = "stereo_width"]
+    stereo_width: FloatParam,
+    #[id = "pan_mode"]
+    pan_mode: EnumParam<PanMode>,
+    #[id = "pan_amount"]
+    pan_amount: FloatParam,
     #[id = "attack_ms"]
     attack_ms: FloatParam,
+    #[id = "hold_ms"]
+    hold_ms: FloatParam,
+    #[id = "decay_ms"]
+    decay_ms: FloatParam,
+    #[id = "sustain"]
+    sustain: FloatParam,
     #[id = "release_ms"]
     release_ms: FloatParam,
+    #[id = "attack_curve"]
+    attack_curve: EnumParam<EnvelopeCurve>,
+    #[id = "decay_curve"]
+    decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "release_curve"]
+    release_curve: EnumParam<EnvelopeCurve>,
     #[id = "partial_count"]
     partial_count: IntParam,
     #[id = "partial_offset"]
     partial_offset: IntParam,
     #[id = "distribution_mode"]
     distribution_mode: EnumParam<DistributionMode>,
+    #[id = "inharmonicity"]
+    inharmonicity: FloatParam,
+    #[id = "stretch"]
+    stretch: FloatParam,
+    #[id = "odd_even_shift"]
+    odd_even_shift: FloatParam,
+    #[id = "sync"]
+    sync: BoolParam,
     #[id = "basic_gain_mode"]
     basic_gain_mode: EnumParam<BasicGainMode>,
+    #[id = "frame_interpolation"]
+    frame_interpolation: EnumParam<FrameInterpolation>,
     #[id = "slew_limiting"]
     slew_limiting: BoolParam,
+    #[id = "slew_mode"]
+    slew_mode: EnumParam<SlewMode>,
+    #[id = "slew_rise_ms"]
+    slew_rise_ms: FloatParam,
+    #[id = "slew_fall_ms"]
+    slew_fall_ms: FloatParam,
+    #[id = "slew_tracking"]
+    slew_tracking: FloatParam,
+    #[id = "synthesis_backend"]
+    synthesis_backend: EnumParam<SynthesisBackend>,
+    #[id = "polyphony"]
+    polyphony: IntParam,
+    #[id = "voice_stealing"]
+    voice_stealing: EnumParam<VoiceStealing>,
+    #[id = "unison"]
+    unison: IntParam,
+    #[id = "unison_detune"]
+    unison_detune: FloatParam,
+    #[id = "unison_detune_curve"]
+    unison_detune_curve: EnumParam<DetuneCurve>,
+    #[id = "unison_spread"]
+    unison_spread: FloatParam,
+    #[id = "velocity_sensitivity"]
+    velocity_sensitivity: FloatParam,
+    #[id = "velocity_tilt"]
+    velocity_tilt: FloatParam,
+    #[id = "reference_pitch"]
+    reference_pitch: FloatParam,
+    #[id = "glide_ms"]
+    glide_ms: FloatParam,
+    #[id = "glide_mode"]
+    glide_mode: EnumParam<GlideMode>,
+    #[id = "glide_legato"]
+    glide_legato: BoolParam,
+    #[id = "bend_range"]
+    bend_range: FloatParam,
+    #[id = "mpe"]
+    mpe: BoolParam,
+    #[id = "mpe_bend_range"]
+    mpe_bend_range: FloatParam,
+    #[id = "pressure_target"]
+    pressure_target: EnumParam<ExpressionTarget>,
+    #[id = "pressure_amount"]
+    pressure_amount: FloatParam,
+    #[id = "timbre_target"]
+    timbre_target: EnumParam<ExpressionTarget>,
+    #[id = "timbre_amount"]
+    timbre_amount: FloatParam,
+
+    /// Whether the demodulator is locked onto the CV's sync markers, updated from the audio
+    /// thread for the editor's sync indicator.
+    pub sync_locked: Arc<AtomicBool>,
+
+    #[persist = "editor-state"]
+    editor_state: Arc<EguiState>,
+
+    /// The Scala tuning, applied when the plugin is initialized.
+    #[persist = "tuning"]
+    pub tuning: RwLock<TuningFiles>,
+    /// A tuning loaded in the editor, parsed there and picked up by the audio thread.
+    pub pending_tuning: Mutex<Option<Tuning>>,
+}
+
+/// One point of the user lookup table, used by [`TransferCurve::Table`].
+#[derive(Params)]
+pub struct TransferPointParams {
+    #[id = "transfer_point"]
+    level: FloatParam,
+}
+
+impl TransferPointParams {
+    fn new(index: usize) -> Self {
+        Self {
+            // defaults to a linear ramp
+            level: FloatParam::new(
+                format!("transfer point {}", index + 1),
+                (index + 1) as f32 / TRANSFER_TABLE_POINTS as f32,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_step_size(0.001),
+        }
+    }
 }
 
 impl Default for SynthPlugin {
     fn default() -> Self {
-        let mut envelope_values = Vec::new();
-        envelope_values.resize_with(4096, || 0.0);
-
         Self {
             params: Arc::new(SynthParams::default()),
-            voice: AdditiveVoice::default(),
-            demodulator: CVDemodulator::default(),
-            sample_rate: 44100.0,
+            synth: Synth::default(),
         }
     }
 }
@@ -68,6 +347,25 @@ impl Default for SynthPlugin {
 impl Default for SynthParams {
     fn default() -> Self {
         Self {
+            cv_source: EnumParam::new("cv source", CvSource::Main),
+            dry_level: FloatParam::new("dry level", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            cv_layout: EnumParam::new("cv layout", CvLayout::Stereo),
+            phase_mode: EnumParam::new("phase mode", PhaseMode::Glide),
+            frame_rate: FloatParam::new(
+                "frame rate",
+                DEFAULT_FRAME_RATE,
+                FloatRange::Skewed {
+                    min: MIN_FRAME_RATE,
+                    max: 200.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_step_size(0.01)
+            .non_automatable(),
             floor: FloatParam::new(
                 "floor",
                 0.0,
@@ -95,7 +393,46 @@ impl Default for SynthParams {
                 },
             )
             .with_step_size(1.0 / 64.0),
-
+            transfer_curve: EnumParam::new("transfer curve", TransferCurve::SignedSquare),
+            decibel_min: FloatParam::new(
+                "decibel just above floor",
+                -96.0,
+                FloatRange::Linear {
+                    min: -144.0,
+                    max: -24.0,
+                },
+            )
+            .with_unit(" dB")
+            .with_step_size(0.1),
+            decibel_max: FloatParam::new(
+                "decibel at 1",
+                0.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 12.0,
+                },
+            )
+            .with_unit(" dB")
+            .with_step_size(0.1),
+            transfer_table: std::array::from_fn(TransferPointParams::new),
+
+            stereo_width: FloatParam::new(
+                "stereo width",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 2.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            pan_mode: EnumParam::new("pan mode", PanMode::Off),
+            pan_amount: FloatParam::new(
+                "pan amount",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
             attack_ms: FloatParam::new(
                 "attack",
                 0.5,
@@ -105,33 +442,258 @@ impl Default for SynthParams {
                     factor: FloatRange::skew_factor(-2.5),
                 },
             )
+            .with_poly_modulation_id(ATTACK_POLY_MOD_ID)
             .with_unit(" ms")
             .with_step_size(0.001),
+            hold_ms: FloatParam::new(
+                "hold",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_poly_modulation_id(HOLD_POLY_MOD_ID)
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            decay_ms: FloatParam::new(
+                "decay",
+                100.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 10000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_poly_modulation_id(DECAY_POLY_MOD_ID)
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            sustain: FloatParam::new("sustain", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_poly_modulation_id(SUSTAIN_POLY_MOD_ID)
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
             release_ms: FloatParam::new(
                 "release",
                 0.5,
                 FloatRange::Skewed {
                     min: 0.0,
-                    max: 50.0,
-                    factor: FloatRange::skew_factor(-2.5),
+                    max: 10000.0,
+                    factor: FloatRange::skew_factor(-2.0),
                 },
             )
+            .with_poly_modulation_id(RELEASE_POLY_MOD_ID)
             .with_unit(" ms")
             .with_step_size(0.001),
+            attack_curve: EnumParam::new("attack curve", EnvelopeCurve::Exponential),
+            decay_curve: EnumParam::new("decay curve", EnvelopeCurve::Exponential),
+            release_curve: EnumParam::new("release curve", EnvelopeCurve::Exponential),
 
             partial_count: IntParam::new(
                 "partial count",
                 500,
-                IntRange::Linear { min: 1, max: 512 },
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_HARMONICS as i32,
+                },
             ),
             partial_offset: IntParam::new(
                 "partial offset",
                 0,
-                IntRange::Linear { min: 0, max: 512 },
+                IntRange::Linear {
+                    min: 0,
+                    max: MAX_HARMONICS as i32,
+                },
             ),
             distribution_mode: EnumParam::new("distribution mode", DistributionMode::Exponential),
+            inharmonicity: FloatParam::new(
+                "inharmonicity",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 0.01,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(5))
+            .with_step_size(0.00001),
+            stretch: FloatParam::new("stretch", 1.0, FloatRange::Linear { min: 0.8, max: 1.2 })
+                .with_value_to_string(formatters::v2s_f32_rounded(4))
+                .with_step_size(0.0001),
+            odd_even_shift: FloatParam::new(
+                "odd/even shift",
+                0.0,
+                FloatRange::Linear {
+                    min: -12.0,
+                    max: 12.0,
+                },
+            )
+            .with_unit(" st")
+            .with_step_size(0.01),
+            sync: BoolParam::new("sync marker", false),
             basic_gain_mode: EnumParam::new("basic gain mode", BasicGainMode::Sawtooth),
+            frame_interpolation: EnumParam::new("frame interpolation", FrameInterpolation::Off),
             slew_limiting: BoolParam::new("slew limiting", true),
+            slew_mode: EnumParam::new("slew mode", SlewMode::Linear),
+            slew_rise_ms: FloatParam::new(
+                "slew rise",
+                DEFAULT_SLEW_MS,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.01),
+            slew_fall_ms: FloatParam::new(
+                "slew fall",
+                DEFAULT_SLEW_MS,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.01),
+            slew_tracking: FloatParam::new(
+                "slew tracking",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            synthesis_backend: EnumParam::new("synthesis", SynthesisBackend::OscillatorBank),
+
+            polyphony: IntParam::new(
+                "polyphony",
+                8,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_POLYPHONY as i32,
+                },
+            ),
+            voice_stealing: EnumParam::new("voice stealing", VoiceStealing::Oldest),
+            unison: IntParam::new(
+                "unison",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_UNISON as i32,
+                },
+            ),
+            unison_detune: FloatParam::new(
+                "unison detune",
+                20.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 100.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_poly_modulation_id(UNISON_DETUNE_POLY_MOD_ID)
+            .with_unit(" ct")
+            .with_step_size(0.1),
+            unison_detune_curve: EnumParam::new("unison detune curve", DetuneCurve::Linear),
+            unison_spread: FloatParam::new(
+                "unison spread",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_sensitivity: FloatParam::new(
+                "velocity sensitivity",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_tilt: FloatParam::new(
+                "velocity to tilt",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(VELOCITY_TILT_POLY_MOD_ID)
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            reference_pitch: FloatParam::new(
+                "reference pitch",
+                DEFAULT_REFERENCE_PITCH,
+                FloatRange::Linear {
+                    min: 400.0,
+                    max: 480.0,
+                },
+            )
+            .with_unit(" Hz")
+            .with_step_size(0.01),
+            glide_ms: FloatParam::new(
+                "glide",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_poly_modulation_id(GLIDE_POLY_MOD_ID)
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            glide_mode: EnumParam::new("glide mode", GlideMode::ConstantTime),
+            glide_legato: BoolParam::new("legato glide", false),
+            bend_range: FloatParam::new(
+                "bend range",
+                DEFAULT_BEND_RANGE,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 48.0,
+                },
+            )
+            .with_unit(" st")
+            .with_step_size(1.0),
+            mpe: BoolParam::new("mpe", false),
+            mpe_bend_range: FloatParam::new(
+                "mpe bend range",
+                DEFAULT_MPE_BEND_RANGE,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 96.0,
+                },
+            )
+            .with_unit(" st")
+            .with_step_size(1.0),
+            pressure_target: EnumParam::new("pressure target", ExpressionTarget::Off),
+            pressure_amount: FloatParam::new(
+                "pressure amount",
+                1.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+            timbre_target: EnumParam::new("timbre target", ExpressionTarget::Off),
+            timbre_amount: FloatParam::new(
+                "timbre amount",
+                1.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(0.01),
+
+            sync_locked: Arc::new(AtomicBool::new(false)),
+            editor_state: editor::default_state(),
+            tuning: RwLock::new(TuningFiles::default()),
+            pending_tuning: Mutex::new(None),
         }
     }
 }
@@ -144,15 +706,32 @@ impl Plugin for SynthPlugin {
 
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        names: PortNames::const_default(),
-    }];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("stereo with cv sidechain"),
+                main_input: Some("input"),
+                main_output: Some("output"),
+                aux_inputs: &["cv"],
+                aux_outputs: &[],
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames::const_default(),
+        },
+    ];
 
     const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
@@ -166,117 +745,120 @@ impl Plugin for SynthPlugin {
         self.params.clone()
     }
 
+    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        editor::create(self.params.clone())
+    }
+
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
-        context.set_latency_samples(DEMOD_BLOCK_SIZE as u32);
+        self.synth.set_sample_rate(buffer_config.sample_rate);
+        // the state is restored before this is called, and parsing allocates. It also covers any
+        // tuning the editor loaded that the audio thread hasn't picked up yet.
+        self.params.pending_tuning.lock().unwrap().take();
+        match Tuning::from_files(&self.params.tuning.read().unwrap()) {
+            Ok(tuning) => self.synth.tuning = tuning,
+            Err(err) => nih_error!("invalid tuning: {err}"),
+        }
+        self.synth
+            .update_frame_size(&SynthSettings::from_params(&self.params));
+        context.set_latency_samples(self.synth.latency_samples());
 
         true
     }
 
     fn reset(&mut self) {
-        self.voice.reset();
+        self.synth.reset();
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let num_samples = buffer.samples();
-
         let buf = buffer.as_slice();
         let (buf_l, buf_r) = buf.split_at_mut(1);
         let buf_l = &mut buf_l[0];
         let buf_r = &mut buf_r[0];
 
-        self.voice
-            .envelope
-            .set_attack_time(self.sample_rate, self.params.attack_ms.value());
-        self.voice
-            .envelope
-            .set_release_time(self.sample_rate, self.params.release_ms.value());
-
-        let num_partials = self.params.partial_count.value() as usize;
-        let partial_offset = self.params.partial_offset.value() as usize;
-        let distribution_mode = self.params.distribution_mode.value();
-        let basic_gain_mode = self.params.basic_gain_mode.value();
-
-        let cv_floor = self.params.floor.value();
-        let cv_ceil = self.params.ceiling.value();
-        let cv_bias = self.params.bias.value();
-        let slew_limiting = self.params.slew_limiting.value();
-
-        let mut note_event = context.next_event();
-        let mut block_start = 0;
-        let mut block_end = (block_start + 64).min(num_samples);
-
-        while block_start < num_samples {
-            'events: loop {
-                match note_event {
-                    Some(event) if (event.timing() as usize) <= block_start => {
-                        match event {
-                            NoteEvent::NoteOn { note, .. } => {
-                                self.voice.note_on(note);
-                                self.demodulator.reset();
-                            }
-                            NoteEvent::NoteOff { .. } => {
-                                self.voice.note_off();
-                            }
-                            NoteEvent::MidiPitchBend { value, .. } => {
-                                self.voice.midi_pitch_bend(value);
-                            }
-                            _ => {}
-                        }
-
-                        note_event = context.next_event();
-                    }
-                    Some(event) if (event.timing() as usize) < block_end => {
-                        block_end = event.timing() as usize;
-                        break 'events;
-                    }
-                    _ => break 'events,
-                }
-            }
+        let settings = SynthSettings::from_params(&self.params);
 
-            let amps = self.demodulator.submit_samples(
-                &buf_l[block_start..block_end],
-                &buf_r[block_start..block_end],
-                &distribution_mode,
-                num_partials,
-                partial_offset,
-                cv_floor,
-                cv_ceil,
-                cv_bias,
-            );
-            if let Some((amp_l, amp_r)) = amps {
-                self.voice.engine.submit_amplitudes(&amp_l, &amp_r);
+        // without a sidechain port in the current layout, CV can only come from the main input
+        let sidechain = match aux.inputs.first() {
+            Some(sidechain) if settings.cv_source == CvSource::Sidechain => {
+                let sidechain = sidechain.as_slice_immutable();
+                Some((&*sidechain[0], &*sidechain[1]))
             }
+            _ => None,
+        };
+
+        // the editor only ever holds the lock briefly, so a missed tuning is picked up next time
+        if let Some(tuning) = self
+            .params
+            .pending_tuning
+            .try_lock()
+            .ok()
+            .and_then(|mut pending| pending.take())
+        {
+            self.synth.tuning = tuning;
+        }
 
-            buf_l[block_start..block_end].fill(0.0);
-            buf_r[block_start..block_end].fill(0.0);
-
-            self.voice.process(
-                self.sample_rate,
-                &mut buf_l[block_start..block_end],
-                &mut buf_r[block_start..block_end],
-                &basic_gain_mode,
-                slew_limiting,
-            );
-
-            block_start = block_end;
-            block_end = (block_start + 64).min(num_samples);
+        if self.synth.update_frame_size(&settings) {
+            context.set_latency_samples(self.synth.latency_samples());
         }
+        context.set_current_voice_capacity(settings.polyphony as u32);
+
+        self.synth.process(
+            buf_l,
+            buf_r,
+            sidechain,
+            &self.params,
+            &settings,
+            &mut HostEvents(context),
+        );
+        self.params
+            .sync_locked
+            .store(self.synth.demodulator.is_sync_locked(), Ordering::Relaxed);
 
         ProcessStatus::Normal
     }
 }
 
+struct HostEvents<'a, C>(&'a mut C);
+
+impl<C: ProcessContext<SynthPlugin>> NoteEventIo for HostEvents<'_, C> {
+    fn next_event(&mut self) -> Option<NoteEvent<()>> {
+        self.0.next_event()
+    }
+
+    fn send_event(&mut self, event: NoteEvent<()>) {
+        self.0.send_event(event);
+    }
+}
+
+impl ClapPlugin for SynthPlugin {
+    const CLAP_ID: &'static str = "codes.som.athenic-demodulator";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some(env!("CARGO_PKG_DESCRIPTION"));
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::Instrument,
+        ClapFeature::AudioEffect,
+        ClapFeature::Synthesizer,
+        ClapFeature::Stereo,
+    ];
+
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        max_voice_capacity: MAX_POLYPHONY as u32,
+        supports_overlapping_voices: true,
+    });
+}
+
 impl Vst3Plugin for SynthPlugin {
     const VST3_CLASS_ID: [u8; 16] = *b"CharAddDemod\0\0\0\0";
 
@@ -284,4 +866,5 @@ impl Vst3Plugin for SynthPlugin {
         &[Vst3SubCategory::Fx, Vst3SubCategory::Synth];
 }
 
-nih_export_vst3!(SynthPlugin);
+nih_export_clap!(SynthPlugin, ModulatorPlugin);
+nih_export_vst3!(SynthPlugin, ModulatorPlugin);
@@ -0,0 +1,235 @@
+use crate::{
+    additive_engine::MAX_HARMONICS,
+    demodulator::{frame_size, DEFAULT_FRAME_RATE, MIN_FRAME_RATE},
+    encoder::CVEncoder,
+    DistributionMode,
+};
+use nih_plug::prelude::*;
+use std::sync::Arc;
+
+pub struct ModulatorPlugin {
+    params: Arc<ModulatorParams>,
+    encoder: CVEncoder,
+    sample_rate: f32,
+}
+
+#[derive(Enum, PartialEq, Debug)]
+pub enum Waveform {
+    Sawtooth,
+    Square,
+    Triangle,
+    Sine,
+}
+
+#[derive(Params)]
+struct ModulatorParams {
+    #[id = "frame_rate"]
+    frame_rate: FloatParam,
+    #[id = "floor"]
+    floor: FloatParam,
+    #[id = "ceiling"]
+    ceiling: FloatParam,
+    #[id = "bias"]
+    bias: FloatParam,
+    #[id = "partial_count"]
+    partial_count: IntParam,
+    #[id = "partial_offset"]
+    partial_offset: IntParam,
+    #[id = "distribution_mode"]
+    distribution_mode: EnumParam<DistributionMode>,
+    #[id = "sync"]
+    sync: BoolParam,
+    #[id = "waveform"]
+    waveform: EnumParam<Waveform>,
+    #[id = "level"]
+    level: FloatParam,
+}
+
+impl Default for ModulatorPlugin {
+    fn default() -> Self {
+        Self {
+            params: Arc::new(ModulatorParams::default()),
+            encoder: CVEncoder::default(),
+            sample_rate: 44100.0,
+        }
+    }
+}
+
+impl Default for ModulatorParams {
+    fn default() -> Self {
+        Self {
+            frame_rate: FloatParam::new(
+                "frame rate",
+                DEFAULT_FRAME_RATE,
+                FloatRange::Skewed {
+                    min: MIN_FRAME_RATE,
+                    max: 200.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" Hz")
+            .with_step_size(0.01)
+            .non_automatable(),
+            floor: FloatParam::new(
+                "floor",
+                0.0,
+                FloatRange::Linear {
+                    min: -2.0,
+                    max: 2.0,
+                },
+            )
+            .with_step_size(1.0 / 32.0),
+            ceiling: FloatParam::new(
+                "ceiling",
+                2.0,
+                FloatRange::Linear {
+                    min: -2.0,
+                    max: 2.0,
+                },
+            )
+            .with_step_size(1.0 / 32.0),
+            bias: FloatParam::new(
+                "bias",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_step_size(1.0 / 64.0),
+
+            partial_count: IntParam::new(
+                "partial count",
+                500,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_HARMONICS as i32,
+                },
+            ),
+            partial_offset: IntParam::new(
+                "partial offset",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: MAX_HARMONICS as i32,
+                },
+            ),
+            distribution_mode: EnumParam::new("distribution mode", DistributionMode::Exponential),
+            sync: BoolParam::new("sync marker", false),
+
+            waveform: EnumParam::new("waveform", Waveform::Sawtooth),
+            level: FloatParam::new("level", 1.0, FloatRange::Linear { min: 0.0, max: 4.0 })
+                .with_step_size(0.01),
+        }
+    }
+}
+
+impl Plugin for ModulatorPlugin {
+    const NAME: &'static str = "athenic modulator";
+    const VENDOR: &'static str = "charlotte athena som";
+    const URL: &'static str = env!("CARGO_PKG_HOMEPAGE");
+    const EMAIL: &'static str = "charlotte@som.codes";
+
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
+        main_input_channels: None,
+        main_output_channels: NonZeroU32::new(2),
+
+        aux_input_ports: &[],
+        aux_output_ports: &[],
+
+        names: PortNames::const_default(),
+    }];
+
+    const MIDI_INPUT: MidiConfig = MidiConfig::None;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
+
+    const SAMPLE_ACCURATE_AUTOMATION: bool = false;
+
+    type SysExMessage = ();
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+
+        true
+    }
+
+    fn reset(&mut self) {
+        self.encoder.reset();
+    }
+
+    fn process(
+        &mut self,
+        buffer: &mut Buffer,
+        _aux: &mut AuxiliaryBuffers,
+        _context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let buf = buffer.as_slice();
+        let (buf_l, buf_r) = buf.split_at_mut(1);
+        let buf_l = &mut buf_l[0];
+        let buf_r = &mut buf_r[0];
+
+        self.encoder
+            .set_frame_size(frame_size(self.sample_rate, self.params.frame_rate.value()));
+
+        let waveform = self.params.waveform.value();
+        let level = self.params.level.value();
+
+        let mut amps = [0.0; MAX_HARMONICS];
+        for (i, amp) in amps.iter_mut().enumerate() {
+            let n = (i + 1) as f32;
+            let odd = i % 2 == 0;
+            *amp = level
+                * match waveform {
+                    Waveform::Sawtooth => 1.0 / n,
+                    Waveform::Square if odd => 1.0 / n,
+                    Waveform::Triangle if odd => 1.0 / (n * n),
+                    Waveform::Sine if i == 0 => 1.0,
+                    _ => 0.0,
+                };
+        }
+        self.encoder.submit_amplitudes(&amps, &amps);
+
+        self.encoder.generate_samples(
+            buf_l,
+            buf_r,
+            &self.params.distribution_mode.value(),
+            self.params.partial_count.value() as usize,
+            self.params.partial_offset.value() as usize,
+            self.params.floor.value(),
+            self.params.ceiling.value(),
+            self.params.bias.value(),
+            self.params.sync.value(),
+        );
+
+        ProcessStatus::Normal
+    }
+}
+
+impl ClapPlugin for ModulatorPlugin {
+    const CLAP_ID: &'static str = "codes.som.athenic-modulator";
+    const CLAP_DESCRIPTION: Option<&'static str> =
+        Some("encodes partial amplitudes as athenic demodulator CV");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+
+    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::Utility, ClapFeature::Stereo];
+}
+
+impl Vst3Plugin for ModulatorPlugin {
+    const VST3_CLASS_ID: [u8; 16] = *b"CharAddModulator";
+
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] =
+        &[Vst3SubCategory::Generator, Vst3SubCategory::Tools];
+}
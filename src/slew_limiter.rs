@@ -0,0 +1,175 @@
+use crate::{additive_engine::MAX_HARMONICS, SlewMode};
+
+/// Default rise and fall time. In linear mode this is the time for a full-scale change, i.e. the
+/// original fixed limit of 12.5 per second; in one-pole mode it is the time constant.
+pub const DEFAULT_SLEW_MS: f32 = 80.0;
+
+/// Per-partial rise and fall rates, recomputed only when the slew settings change.
+pub struct SlewLimiter {
+    enabled: bool,
+    one_pole: bool,
+    sample_rate: f32,
+    rise_ms: f32,
+    fall_ms: f32,
+    tracking: f32,
+    /// Per sample: the largest amplitude step in linear mode, or the filter coefficient in
+    /// one-pole mode.
+    rise: [f32; MAX_HARMONICS],
+    fall: [f32; MAX_HARMONICS],
+}
+
+impl Default for SlewLimiter {
+    fn default() -> Self {
+        let mut this = Self {
+            enabled: true,
+            one_pole: false,
+            sample_rate: 44100.0,
+            rise_ms: DEFAULT_SLEW_MS,
+            fall_ms: DEFAULT_SLEW_MS,
+            tracking: 0.0,
+            rise: [0.0; MAX_HARMONICS],
+            fall: [0.0; MAX_HARMONICS],
+        };
+        this.compute_rates();
+        this
+    }
+}
+
+impl SlewLimiter {
+    /// `tracking` scales each partial's times by `(n + 1)^-tracking`, so positive values make
+    /// higher partials move faster and negative values slower.
+    pub fn update(
+        &mut self,
+        sample_rate: f32,
+        enabled: bool,
+        mode: &SlewMode,
+        rise_ms: f32,
+        fall_ms: f32,
+        tracking: f32,
+    ) {
+        self.enabled = enabled;
+
+        let one_pole = *mode == SlewMode::OnePole;
+        if one_pole == self.one_pole
+            && sample_rate == self.sample_rate
+            && rise_ms == self.rise_ms
+            && fall_ms == self.fall_ms
+            && tracking == self.tracking
+        {
+            return;
+        }
+
+        self.one_pole = one_pole;
+        self.sample_rate = sample_rate;
+        self.rise_ms = rise_ms;
+        self.fall_ms = fall_ms;
+        self.tracking = tracking;
+        self.compute_rates();
+    }
+
+    fn compute_rates(&mut self) {
+        let ms_to_samples = self.sample_rate / 1000.0;
+        for i in 0..MAX_HARMONICS {
+            let scale = ((i + 1) as f32).powf(-self.tracking);
+            self.rise[i] = self.rate(self.rise_ms * scale * ms_to_samples);
+            self.fall[i] = self.rate(self.fall_ms * scale * ms_to_samples);
+        }
+    }
+
+    fn rate(&self, time_samples: f32) -> f32 {
+        let time_samples = time_samples.max(1.0);
+        if self.one_pole {
+            1.0 - f32::exp(-1.0 / time_samples)
+        } else {
+            1.0 / time_samples
+        }
+    }
+
+    /// Moves partial `i` from `last` towards `target` by `samples` samples' worth of slew. Moving
+    /// away from zero counts as rising.
+    #[inline]
+    pub fn next(&self, i: usize, last: f32, target: f32, samples: usize) -> f32 {
+        if !self.enabled {
+            return target;
+        }
+
+        let rate = if target.abs() > last.abs() {
+            self.rise[i]
+        } else {
+            self.fall[i]
+        };
+
+        if self.one_pole {
+            let coefficient = if samples == 1 {
+                rate
+            } else {
+                1.0 - (1.0 - rate).powi(samples as i32)
+            };
+            last + (target - last) * coefficient
+        } else {
+            let limit = rate * samples as f32;
+            last + (target - last).clamp(-limit, limit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+    const RISE_MS: f32 = 10.0;
+    const FALL_MS: f32 = 40.0;
+
+    fn limiter(mode: &SlewMode) -> SlewLimiter {
+        let mut slew = SlewLimiter::default();
+        slew.update(SAMPLE_RATE, true, mode, RISE_MS, FALL_MS, 0.0);
+        slew
+    }
+
+    /// Runs partial 0 from `from` towards `to` a sample at a time.
+    fn run(slew: &SlewLimiter, from: f32, to: f32, samples: usize) -> f32 {
+        (0..samples).fold(from, |last, _| slew.next(0, last, to, 1))
+    }
+
+    #[test]
+    fn linear_steps_rise_and_fall_in_their_own_times() {
+        let slew = limiter(&SlewMode::Linear);
+        // a sample per millisecond
+        let (rise, fall) = (RISE_MS as usize, FALL_MS as usize);
+
+        assert!(run(&slew, 0.0, 1.0, rise - 1) < 1.0);
+        assert!((run(&slew, 0.0, 1.0, rise) - 1.0).abs() < 1e-5);
+        assert!(run(&slew, 1.0, 0.0, fall - 1) > 0.0);
+        assert!(run(&slew, 1.0, 0.0, fall).abs() < 1e-5);
+
+        // moving away from zero is rising, whatever the sign
+        assert!((run(&slew, 0.0, -1.0, rise) + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn one_pole_steps_rise_and_fall_with_their_own_time_constants() {
+        let slew = limiter(&SlewMode::OnePole);
+        let (rise, fall) = (RISE_MS as usize, FALL_MS as usize);
+        let decayed = (-1.0f32).exp();
+
+        assert!((run(&slew, 0.0, 1.0, rise) - (1.0 - decayed)).abs() < 1e-3);
+        assert!((run(&slew, 1.0, 0.0, fall) - decayed).abs() < 1e-3);
+    }
+
+    #[test]
+    fn steps_of_several_samples_match_single_samples() {
+        for mode in [SlewMode::Linear, SlewMode::OnePole] {
+            let slew = limiter(&mode);
+            let at_once = slew.next(0, 0.0, 1.0, 5);
+            assert!((at_once - run(&slew, 0.0, 1.0, 5)).abs() < 1e-5, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn disabled_jumps_straight_to_the_target() {
+        let mut slew = limiter(&SlewMode::Linear);
+        slew.update(SAMPLE_RATE, false, &SlewMode::Linear, RISE_MS, FALL_MS, 0.0);
+        assert_eq!(slew.next(0, 0.0, 1.0, 1), 1.0);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{additive_engine::MAX_HARMONICS, PanMode};
+
+/// Octaves above the fundamental that the frequency pan sweeps across, from the fundamental on
+/// the left to the highest partial on the right.
+const PAN_OCTAVES: f64 = 9.0;
+
+/// Widens or narrows a decoded pair of spectra and pans their partials. Mid/side CV arrives here
+/// as left and right, already matrixed by the demodulator's [`crate::CvLayout::MidSide`] layout.
+pub struct Stereo<'a> {
+    /// Side level relative to mid, so 0 is mono and 1 leaves the spectra as they are. Above 1,
+    /// each partial only widens until one side is silent.
+    pub width: f32,
+    pub pan_mode: &'a PanMode,
+    /// How far partials are panned, where 1 reaches either side.
+    pub pan_amount: f32,
+}
+
+impl Stereo<'_> {
+    /// Applies the width and panning to a frame. `ratios` places each partial for the
+    /// frequency-dependent pan.
+    #[allow(clippy::needless_range_loop)]
+    pub fn apply(
+        &self,
+        amp_l: &mut [f32; MAX_HARMONICS],
+        amp_r: &mut [f32; MAX_HARMONICS],
+        ratios: &[f64; MAX_HARMONICS],
+    ) {
+        for i in 0..MAX_HARMONICS {
+            let mid = (amp_l[i] + amp_r[i]) * 0.5;
+            let side = (amp_l[i] - amp_r[i]) * 0.5;
+            // widening stops where one side falls silent, rather than flipping its polarity
+            let limit = mid.abs().max(side.abs());
+            let side = (side * self.width).clamp(-limit, limit);
+            let (left, right) = (mid + side, mid - side);
+
+            // a balance law, so panning never moves one side's spectrum into the other
+            let pan = self.pan(i, ratios[i]) * self.pan_amount;
+            amp_l[i] = left * (1.0 - pan).min(1.0);
+            amp_r[i] = right * (1.0 + pan).min(1.0);
+        }
+    }
+
+    /// Partial `i`'s pan position at full amount, from -1 for left to 1 for right.
+    fn pan(&self, i: usize, ratio: f64) -> f32 {
+        match self.pan_mode {
+            PanMode::Off => 0.0,
+            // the fundamental is the first, odd, partial
+            PanMode::OddEven => {
+                if i.is_multiple_of(2) {
+                    -1.0
+                } else {
+                    1.0
+                }
+            }
+            PanMode::Frequency => {
+                let octave = ratio.max(f64::MIN_POSITIVE).log2() / PAN_OCTAVES;
+                (octave.clamp(0.0, 1.0) * 2.0 - 1.0) as f32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(width: f32, left: f32, right: f32) -> (f32, f32) {
+        let mut amp_l = [0.0; MAX_HARMONICS];
+        let mut amp_r = [0.0; MAX_HARMONICS];
+        amp_l[0] = left;
+        amp_r[0] = right;
+        Stereo {
+            width,
+            pan_mode: &PanMode::Off,
+            pan_amount: 0.0,
+        }
+        .apply(&mut amp_l, &mut amp_r, &[1.0; MAX_HARMONICS]);
+        (amp_l[0], amp_r[0])
+    }
+
+    #[test]
+    fn width() {
+        assert_eq!(apply(1.0, 1.0, 0.5), (1.0, 0.5));
+        assert_eq!(apply(0.0, 1.0, 0.5), (0.75, 0.75));
+        assert_eq!(apply(1.0, 1.0, -0.5), (1.0, -0.5));
+    }
+
+    #[test]
+    fn widening_keeps_polarity() {
+        assert_eq!(apply(2.0, 1.0, 0.5), (1.25, 0.25));
+        assert_eq!(apply(2.0, 1.0, 0.0), (1.0, 0.0));
+        assert_eq!(apply(2.0, -1.0, -0.5), (-1.25, -0.25));
+        // already past silence on one side, so left as it is
+        assert_eq!(apply(2.0, 1.0, -0.5), (1.0, -0.5));
+    }
+}
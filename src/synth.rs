@@ -0,0 +1,817 @@
+use crate::{
+    additive_engine::MAX_HARMONICS,
+    delay_line::DelayLine,
+    demodulator::{self, CVDemodulator, MIN_FRAME_RATE},
+    frame_interpolator::FrameInterpolator,
+    slew_limiter::SlewLimiter,
+    stereo::Stereo,
+    transfer_curve::{Transfer, TRANSFER_TABLE_POINTS},
+    tuning::Tuning,
+    voice::{AdditiveVoice, Expression, VoiceModulation},
+    voice_pool::{fallback_voice_id, VoicePool},
+    BasicGainMode, CvLayout, CvSource, DetuneCurve, DistributionMode, EnvelopeCurve,
+    ExpressionTarget, FrameInterpolation, GlideMode, PanMode, PhaseMode, SlewMode, SynthParams,
+    SynthesisBackend, TransferCurve, VoiceStealing, ATTACK_POLY_MOD_ID, DECAY_POLY_MOD_ID,
+    GLIDE_POLY_MOD_ID, HOLD_POLY_MOD_ID, RELEASE_POLY_MOD_ID, SUSTAIN_POLY_MOD_ID,
+    UNISON_DETUNE_POLY_MOD_ID, VELOCITY_TILT_POLY_MOD_ID,
+};
+use nih_plug::prelude::*;
+
+const BLOCK_SIZE: usize = 64;
+/// MPE's timbre controller, "sound controller 5" in plain MIDI.
+const TIMBRE_CC: u8 = 74;
+
+/// Plain-value snapshot of [`SynthParams`], so the synth can also be driven without a host.
+pub struct SynthSettings {
+    pub cv_source: CvSource,
+    pub dry_level: f32,
+    pub cv_layout: CvLayout,
+    pub phase_mode: PhaseMode,
+    pub frame_rate: f32,
+    pub floor: f32,
+    pub ceiling: f32,
+    pub bias: f32,
+    pub transfer_curve: TransferCurve,
+    pub transfer_table: [f32; TRANSFER_TABLE_POINTS],
+    pub decibel_min: f32,
+    pub decibel_max: f32,
+    pub stereo_width: f32,
+    pub pan_mode: PanMode,
+    pub pan_amount: f32,
+    pub attack_ms: f32,
+    pub hold_ms: f32,
+    pub decay_ms: f32,
+    pub sustain: f32,
+    pub release_ms: f32,
+    pub attack_curve: EnvelopeCurve,
+    pub decay_curve: EnvelopeCurve,
+    pub release_curve: EnvelopeCurve,
+    pub partial_count: i32,
+    pub partial_offset: i32,
+    pub distribution_mode: DistributionMode,
+    pub inharmonicity: f32,
+    pub stretch: f32,
+    pub odd_even_shift: f32,
+    pub sync: bool,
+    pub basic_gain_mode: BasicGainMode,
+    pub frame_interpolation: FrameInterpolation,
+    pub slew_limiting: bool,
+    pub slew_mode: SlewMode,
+    pub slew_rise_ms: f32,
+    pub slew_fall_ms: f32,
+    pub slew_tracking: f32,
+    pub synthesis_backend: SynthesisBackend,
+    pub polyphony: i32,
+    pub voice_stealing: VoiceStealing,
+    pub unison: i32,
+    pub unison_detune: f32,
+    pub unison_detune_curve: DetuneCurve,
+    pub unison_spread: f32,
+    pub velocity_sensitivity: f32,
+    pub velocity_tilt: f32,
+    pub reference_pitch: f32,
+    pub glide_ms: f32,
+    pub glide_mode: GlideMode,
+    pub glide_legato: bool,
+    pub bend_range: f32,
+    pub mpe: bool,
+    pub mpe_bend_range: f32,
+    pub pressure_target: ExpressionTarget,
+    pub pressure_amount: f32,
+    pub timbre_target: ExpressionTarget,
+    pub timbre_amount: f32,
+}
+
+impl SynthSettings {
+    pub fn from_params(params: &SynthParams) -> Self {
+        Self {
+            cv_source: params.cv_source.value(),
+            dry_level: params.dry_level.value(),
+            cv_layout: params.cv_layout.value(),
+            phase_mode: params.phase_mode.value(),
+            frame_rate: params.frame_rate.value(),
+            floor: params.floor.value(),
+            ceiling: params.ceiling.value(),
+            bias: params.bias.value(),
+            transfer_curve: params.transfer_curve.value(),
+            transfer_table: std::array::from_fn(|i| params.transfer_table[i].level.value()),
+            decibel_min: params.decibel_min.value(),
+            decibel_max: params.decibel_max.value(),
+            stereo_width: params.stereo_width.value(),
+            pan_mode: params.pan_mode.value(),
+            pan_amount: params.pan_amount.value(),
+            attack_ms: params.attack_ms.value(),
+            hold_ms: params.hold_ms.value(),
+            decay_ms: params.decay_ms.value(),
+            sustain: params.sustain.value(),
+            release_ms: params.release_ms.value(),
+            attack_curve: params.attack_curve.value(),
+            decay_curve: params.decay_curve.value(),
+            release_curve: params.release_curve.value(),
+            partial_count: params.partial_count.value(),
+            partial_offset: params.partial_offset.value(),
+            distribution_mode: params.distribution_mode.value(),
+            inharmonicity: params.inharmonicity.value(),
+            stretch: params.stretch.value(),
+            odd_even_shift: params.odd_even_shift.value(),
+            sync: params.sync.value(),
+            basic_gain_mode: params.basic_gain_mode.value(),
+            frame_interpolation: params.frame_interpolation.value(),
+            slew_limiting: params.slew_limiting.value(),
+            slew_mode: params.slew_mode.value(),
+            slew_rise_ms: params.slew_rise_ms.value(),
+            slew_fall_ms: params.slew_fall_ms.value(),
+            slew_tracking: params.slew_tracking.value(),
+            synthesis_backend: params.synthesis_backend.value(),
+            polyphony: params.polyphony.value(),
+            voice_stealing: params.voice_stealing.value(),
+            unison: params.unison.value(),
+            unison_detune: params.unison_detune.value(),
+            unison_detune_curve: params.unison_detune_curve.value(),
+            unison_spread: params.unison_spread.value(),
+            velocity_sensitivity: params.velocity_sensitivity.value(),
+            velocity_tilt: params.velocity_tilt.value(),
+            reference_pitch: params.reference_pitch.value(),
+            glide_ms: params.glide_ms.value(),
+            glide_mode: params.glide_mode.value(),
+            glide_legato: params.glide_legato.value(),
+            bend_range: params.bend_range.value(),
+            mpe: params.mpe.value(),
+            mpe_bend_range: params.mpe_bend_range.value(),
+            pressure_target: params.pressure_target.value(),
+            pressure_amount: params.pressure_amount.value(),
+            timbre_target: params.timbre_target.value(),
+            timbre_amount: params.timbre_amount.value(),
+        }
+    }
+
+    /// How far pressure and timbre move `target`. Timbre is bipolar around its centre.
+    pub fn expression_amount(&self, target: &ExpressionTarget, expression: &Expression) -> f32 {
+        let mut amount = 0.0;
+        if self.pressure_target == *target {
+            amount += self.pressure_amount * expression.pressure;
+        }
+        if self.timbre_target == *target {
+            amount += self.timbre_amount * (expression.timbre * 2.0 - 1.0);
+        }
+        amount
+    }
+
+    /// Overrides a setting by parameter ID, parsing `value` the same way a host parses typed-in
+    /// parameter values.
+    pub fn set(&mut self, params: &SynthParams, id: &str, value: &str) -> Result<(), String> {
+        fn parse<P: Param>(param: &P, value: &str) -> Result<P::Plain, String> {
+            param
+                .string_to_normalized_value(value)
+                .map(|normalized| param.preview_plain(normalized))
+                .ok_or_else(|| format!("invalid value for {}: {value}", param.name()))
+        }
+
+        if let Some(point) = id.strip_prefix("transfer_point_") {
+            let index = point
+                .parse::<usize>()
+                .ok()
+                .filter(|point| (1..=TRANSFER_TABLE_POINTS).contains(point))
+                .ok_or_else(|| format!("unknown parameter: {id}"))?
+                - 1;
+            self.transfer_table[index] = parse(&params.transfer_table[index].level, value)?;
+            return Ok(());
+        }
+
+        match id {
+            "cv_source" => self.cv_source = parse(&params.cv_source, value)?,
+            "dry_level" => self.dry_level = parse(&params.dry_level, value)?,
+            "cv_layout" => self.cv_layout = parse(&params.cv_layout, value)?,
+            "phase_mode" => self.phase_mode = parse(&params.phase_mode, value)?,
+            "frame_rate" => self.frame_rate = parse(&params.frame_rate, value)?,
+            "floor" => self.floor = parse(&params.floor, value)?,
+            "ceiling" => self.ceiling = parse(&params.ceiling, value)?,
+            "bias" => self.bias = parse(&params.bias, value)?,
+            "transfer_curve" => self.transfer_curve = parse(&params.transfer_curve, value)?,
+            "decibel_min" => self.decibel_min = parse(&params.decibel_min, value)?,
+            "decibel_max" => self.decibel_max = parse(&params.decibel_max, value)?,
+            "stereo_width" => self.stereo_width = parse(&params.stereo_width, value)?,
+            "pan_mode" => self.pan_mode = parse(&params.pan_mode, value)?,
+            "pan_amount" => self.pan_amount = parse(&params.pan_amount, value)?,
+            "attack_ms" => self.attack_ms = parse(&params.attack_ms, value)?,
+            "hold_ms" => self.hold_ms = parse(&params.hold_ms, value)?,
+            "decay_ms" => self.decay_ms = parse(&params.decay_ms, value)?,
+            "sustain" => self.sustain = parse(&params.sustain, value)?,
+            "release_ms" => self.release_ms = parse(&params.release_ms, value)?,
+            "attack_curve" => self.attack_curve = parse(&params.attack_curve, value)?,
+            "decay_curve" => self.decay_curve = parse(&params.decay_curve, value)?,
+            "release_curve" => self.release_curve = parse(&params.release_curve, value)?,
+            "partial_count" => self.partial_count = parse(&params.partial_count, value)?,
+            "partial_offset" => self.partial_offset = parse(&params.partial_offset, value)?,
+            "distribution_mode" => {
+                self.distribution_mode = parse(&params.distribution_mode, value)?
+            }
+            "inharmonicity" => self.inharmonicity = parse(&params.inharmonicity, value)?,
+            "stretch" => self.stretch = parse(&params.stretch, value)?,
+            "odd_even_shift" => self.odd_even_shift = parse(&params.odd_even_shift, value)?,
+            "sync" => self.sync = parse(&params.sync, value)?,
+            "basic_gain_mode" => self.basic_gain_mode = parse(&params.basic_gain_mode, value)?,
+            "frame_interpolation" => {
+                self.frame_interpolation = parse(&params.frame_interpolation, value)?
+            }
+            "slew_limiting" => self.slew_limiting = parse(&params.slew_limiting, value)?,
+            "slew_mode" => self.slew_mode = parse(&params.slew_mode, value)?,
+            "slew_rise_ms" => self.slew_rise_ms = parse(&params.slew_rise_ms, value)?,
+            "slew_fall_ms" => self.slew_fall_ms = parse(&params.slew_fall_ms, value)?,
+            "slew_tracking" => self.slew_tracking = parse(&params.slew_tracking, value)?,
+            "synthesis_backend" => {
+                self.synthesis_backend = parse(&params.synthesis_backend, value)?
+            }
+            "polyphony" => self.polyphony = parse(&params.polyphony, value)?,
+            "voice_stealing" => self.voice_stealing = parse(&params.voice_stealing, value)?,
+            "unison" => self.unison = parse(&params.unison, value)?,
+            "unison_detune" => self.unison_detune = parse(&params.unison_detune, value)?,
+            "unison_detune_curve" => {
+                self.unison_detune_curve = parse(&params.unison_detune_curve, value)?
+            }
+            "unison_spread" => self.unison_spread = parse(&params.unison_spread, value)?,
+            "velocity_sensitivity" => {
+                self.velocity_sensitivity = parse(&params.velocity_sensitivity, value)?
+            }
+            "velocity_tilt" => self.velocity_tilt = parse(&params.velocity_tilt, value)?,
+            "reference_pitch" => self.reference_pitch = parse(&params.reference_pitch, value)?,
+            "glide_ms" => self.glide_ms = parse(&params.glide_ms, value)?,
+            "glide_mode" => self.glide_mode = parse(&params.glide_mode, value)?,
+            "glide_legato" => self.glide_legato = parse(&params.glide_legato, value)?,
+            "bend_range" => self.bend_range = parse(&params.bend_range, value)?,
+            "mpe" => self.mpe = parse(&params.mpe, value)?,
+            "mpe_bend_range" => self.mpe_bend_range = parse(&params.mpe_bend_range, value)?,
+            "pressure_target" => self.pressure_target = parse(&params.pressure_target, value)?,
+            "pressure_amount" => self.pressure_amount = parse(&params.pressure_amount, value)?,
+            "timbre_target" => self.timbre_target = parse(&params.timbre_target, value)?,
+            "timbre_amount" => self.timbre_amount = parse(&params.timbre_amount, value)?,
+            _ => return Err(format!("unknown parameter: {id}")),
+        }
+
+        Ok(())
+    }
+}
+
+/// The synth's connection to the outside world's note events.
+pub trait NoteEventIo {
+    /// The next incoming event for the current buffer, in timing order.
+    fn next_event(&mut self) -> Option<NoteEvent<()>>;
+
+    /// Reports an event back to the host, e.g. a voice terminating.
+    fn send_event(&mut self, event: NoteEvent<()>);
+}
+
+/// The param and per-voice state behind a polyphonic modulation ID.
+fn poly_modulation_target<'a>(
+    params: &'a SynthParams,
+    voice: &'a mut AdditiveVoice,
+    poly_modulation_id: u32,
+) -> Option<(&'a FloatParam, &'a mut Option<VoiceModulation>)> {
+    match poly_modulation_id {
+        ATTACK_POLY_MOD_ID => Some((&params.attack_ms, &mut voice.attack_mod)),
+        HOLD_POLY_MOD_ID => Some((&params.hold_ms, &mut voice.hold_mod)),
+        DECAY_POLY_MOD_ID => Some((&params.decay_ms, &mut voice.decay_mod)),
+        SUSTAIN_POLY_MOD_ID => Some((&params.sustain, &mut voice.sustain_mod)),
+        RELEASE_POLY_MOD_ID => Some((&params.release_ms, &mut voice.release_mod)),
+        UNISON_DETUNE_POLY_MOD_ID => Some((&params.unison_detune, &mut voice.unison_detune_mod)),
+        VELOCITY_TILT_POLY_MOD_ID => Some((&params.velocity_tilt, &mut voice.velocity_tilt_mod)),
+        GLIDE_POLY_MOD_ID => Some((&params.glide_ms, &mut voice.glide_mod)),
+        _ => None,
+    }
+}
+
+/// The latency at the lowest frame rate with frame interpolation, the longest the synth reports.
+fn max_latency_samples(sample_rate: f32) -> usize {
+    2 * demodulator::frame_size(sample_rate, MIN_FRAME_RATE)
+}
+
+fn harmonic_ratios() -> [f64; MAX_HARMONICS] {
+    std::array::from_fn(|n| (n + 1) as f64)
+}
+
+/// Applies stretch, then piano-style inharmonicity `r·√(1 + B·r²)`, then shifts every even
+/// partial by `odd_even_shift` semitones. Only the first `partials` are tuned, as no voice plays
+/// any further.
+fn tune_partials(
+    ratios: &[f64; MAX_HARMONICS],
+    settings: &SynthSettings,
+    partials: usize,
+    tuned_ratios: &mut [f64; MAX_HARMONICS],
+) {
+    let stretch = settings.stretch as f64;
+    let inharmonicity = settings.inharmonicity as f64;
+    let even_shift = 2.0f64.powf(settings.odd_even_shift as f64 / 12.0);
+
+    for (n, (tuned, &ratio)) in tuned_ratios[..partials].iter_mut().zip(ratios).enumerate() {
+        let mut ratio = if stretch == 1.0 {
+            ratio
+        } else {
+            ratio.powf(stretch)
+        };
+        ratio *= (1.0 + inharmonicity * ratio * ratio).sqrt();
+        if n % 2 == 1 {
+            ratio *= even_shift;
+        }
+        *tuned = ratio;
+    }
+}
+
+fn voice_terminated(timing: u32, voice: &AdditiveVoice) -> NoteEvent<()> {
+    NoteEvent::VoiceTerminated {
+        timing,
+        voice_id: Some(voice.voice_id()),
+        channel: voice.channel(),
+        note: voice.note(),
+    }
+}
+
+/// The demodulator and voices, independent of any plugin wrapper.
+pub struct Synth {
+    pub voices: VoicePool,
+    pub demodulator: CVDemodulator,
+    pub frames: FrameInterpolator,
+    pub slew: SlewLimiter,
+    /// Loaded from the plugin state, outside of processing.
+    pub tuning: Tuning,
+    /// Each partial's frequency as a multiple of the fundamental, before tuning.
+    pub ratios: [f64; MAX_HARMONICS],
+    tuned_ratios: [f64; MAX_HARMONICS],
+    /// Snapped phases and ratios held back while interpolating, until the amplitudes reach
+    /// their frame.
+    pending_phases: Option<[f32; MAX_HARMONICS]>,
+    pending_ratios: Option<[f64; MAX_HARMONICS]>,
+    /// Holds the dry signal back by the reported latency, so it stays aligned with the synth.
+    dry_delay: DelayLine,
+    sample_rate: f32,
+    interpolating: bool,
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Self {
+            voices: VoicePool::default(),
+            demodulator: CVDemodulator::default(),
+            frames: FrameInterpolator::default(),
+            slew: SlewLimiter::default(),
+            tuning: Tuning::default(),
+            ratios: harmonic_ratios(),
+            tuned_ratios: harmonic_ratios(),
+            pending_phases: None,
+            pending_ratios: None,
+            dry_delay: DelayLine::new(max_latency_samples(44100.0)),
+            sample_rate: 44100.0,
+            interpolating: false,
+        }
+    }
+}
+
+impl Synth {
+    pub fn reset(&mut self) {
+        self.voices.reset();
+        self.frames.reset();
+        self.dry_delay.reset();
+        self.pending_phases = None;
+        self.pending_ratios = None;
+    }
+
+    /// Sets the sample rate, resizing the dry delay for the longest latency at that rate. This
+    /// allocates, so it has to happen outside of processing.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.dry_delay
+            .set_max_delay(max_latency_samples(sample_rate));
+    }
+
+    /// Resizes the demodulator's frames to match the frame rate, and accounts for the extra frame
+    /// that frame interpolation waits for. Returns `true` if the latency changed.
+    pub fn update_frame_size(&mut self, settings: &SynthSettings) -> bool {
+        let latency = self.latency_samples();
+
+        let frame_size = demodulator::frame_size(self.sample_rate, settings.frame_rate);
+        self.demodulator.set_frame_size(frame_size);
+        self.frames.set_frame_size(frame_size);
+        self.interpolating = settings.frame_interpolation != FrameInterpolation::Off;
+        self.dry_delay.set_delay(self.latency_samples() as usize);
+
+        self.latency_samples() != latency
+    }
+
+    pub fn latency_samples(&self) -> u32 {
+        let frames = if self.interpolating { 2 } else { 1 };
+        (self.demodulator.frame_size() * frames) as u32
+    }
+
+    fn handle_event(
+        &mut self,
+        event: &NoteEvent<()>,
+        params: &SynthParams,
+        settings: &SynthSettings,
+        events: &mut impl NoteEventIo,
+    ) {
+        let timing = event.timing();
+
+        match *event {
+            NoteEvent::NoteOn {
+                voice_id,
+                channel,
+                note,
+                velocity,
+                ..
+            } => {
+                // keys the mapping leaves out don't play
+                if !self.tuning.is_mapped(note) {
+                    return;
+                }
+
+                self.voices.note_on(
+                    voice_id.unwrap_or_else(|| fallback_voice_id(note, channel)),
+                    channel,
+                    note,
+                    velocity,
+                    settings.polyphony as usize,
+                    &settings.voice_stealing,
+                    |voice| events.send_event(voice_terminated(timing, voice)),
+                );
+                // without sync markers, notes are what establish frame alignment
+                if !settings.sync {
+                    self.demodulator.reset();
+                }
+            }
+            NoteEvent::NoteOff {
+                voice_id,
+                channel,
+                note,
+                ..
+            } => {
+                self.voices.note_off(voice_id, channel, note);
+            }
+            NoteEvent::Choke {
+                voice_id,
+                channel,
+                note,
+                ..
+            } => {
+                self.voices.choke(voice_id, channel, note, |voice| {
+                    events.send_event(voice_terminated(timing, voice))
+                });
+            }
+            NoteEvent::PolyModulation {
+                voice_id,
+                poly_modulation_id,
+                normalized_offset,
+                ..
+            } => {
+                if let Some((param, modulation)) = self
+                    .voices
+                    .voice_mut(voice_id)
+                    .and_then(|voice| poly_modulation_target(params, voice, poly_modulation_id))
+                {
+                    *modulation = Some(VoiceModulation {
+                        normalized_offset,
+                        value: param.preview_modulated(normalized_offset),
+                    });
+                }
+            }
+            NoteEvent::MonoAutomation {
+                poly_modulation_id,
+                normalized_value,
+                ..
+            } => {
+                for voice in self.voices.active_voices_mut() {
+                    if let Some((param, Some(modulation))) =
+                        poly_modulation_target(params, voice, poly_modulation_id)
+                    {
+                        modulation.value =
+                            param.preview_plain(normalized_value + modulation.normalized_offset);
+                    }
+                }
+            }
+            NoteEvent::MidiPitchBend { channel, value, .. } => {
+                self.voices.midi_pitch_bend(channel, value);
+            }
+            NoteEvent::MidiChannelPressure {
+                channel, pressure, ..
+            } => {
+                self.voices.midi_channel_pressure(channel, pressure);
+            }
+            NoteEvent::MidiCC {
+                channel,
+                cc: TIMBRE_CC,
+                value,
+                ..
+            } => {
+                self.voices.midi_timbre(channel, value);
+            }
+            NoteEvent::PolyPressure {
+                voice_id,
+                channel,
+                note,
+                pressure,
+                ..
+            } => {
+                self.voices
+                    .note_expression(voice_id, channel, note, |expression| {
+                        expression.pressure = pressure
+                    });
+            }
+            NoteEvent::PolyBrightness {
+                voice_id,
+                channel,
+                note,
+                brightness,
+                ..
+            } => {
+                self.voices
+                    .note_expression(voice_id, channel, note, |expression| {
+                        expression.timbre = brightness
+                    });
+            }
+            _ => {}
+        }
+    }
+
+    /// Applies the data from the right channel that was held back for the frame the amplitudes
+    /// have now reached, so it doesn't arrive a frame early while interpolating.
+    fn apply_pending(&mut self) {
+        if let Some(phases) = self.pending_phases.take() {
+            self.voices.submit_phases(&phases, 0);
+        }
+        if let Some(ratios) = self.pending_ratios.take() {
+            self.ratios = ratios;
+        }
+    }
+
+    /// Demodulates CV from `sidechain`, or from `buf_l`/`buf_r` when there is none, and mixes the
+    /// synthesized output into `buf_l`/`buf_r` at the dry level, or in place of them without a
+    /// sidechain. `params` is only consulted to resolve polyphonic modulation.
+    pub fn process(
+        &mut self,
+        buf_l: &mut [f32],
+        buf_r: &mut [f32],
+        sidechain: Option<(&[f32], &[f32])>,
+        params: &SynthParams,
+        settings: &SynthSettings,
+        events: &mut impl NoteEventIo,
+    ) {
+        assert_eq!(
+            buf_l.len(),
+            buf_r.len(),
+            "channel buffers should have matching sample counts"
+        );
+        let num_samples = buf_l.len();
+        if let Some((cv_l, cv_r)) = sidechain {
+            assert!(
+                cv_l.len() == num_samples && cv_r.len() == num_samples,
+                "sidechain buffers should match the main buffers' sample count"
+            );
+        }
+
+        let partial_offset = settings.partial_offset as usize;
+        let num_partials = if settings.cv_layout == CvLayout::DualResolution {
+            // the right channel's partials have to fit above the left's
+            (settings.partial_count as usize).min(MAX_HARMONICS.saturating_sub(partial_offset) / 2)
+        } else {
+            settings.partial_count as usize
+        };
+
+        // without ratio data, partials fall back to the harmonic series
+        if settings.cv_layout != CvLayout::AmplitudeRatio {
+            self.ratios = harmonic_ratios();
+            self.pending_ratios = None;
+        }
+
+        // the CV can't reach past this partial, so the voices don't need to process any further
+        let reach = partial_offset + num_partials;
+        let budget = if settings.cv_layout == CvLayout::DualResolution {
+            reach + num_partials
+        } else {
+            reach
+        }
+        .min(MAX_HARMONICS);
+        self.voices.set_partial_budget(budget);
+        self.voices.set_mpe(settings.mpe);
+        self.tuning.set_reference_pitch(settings.reference_pitch);
+
+        self.slew.update(
+            self.sample_rate,
+            settings.slew_limiting,
+            &settings.slew_mode,
+            settings.slew_rise_ms,
+            settings.slew_fall_ms,
+            settings.slew_tracking,
+        );
+
+        let mut note_event = events.next_event();
+        let mut block_start = 0;
+        let mut block_end = (block_start + BLOCK_SIZE).min(num_samples);
+
+        while block_start < num_samples {
+            'events: loop {
+                match note_event {
+                    Some(event) if (event.timing() as usize) <= block_start => {
+                        self.handle_event(&event, params, settings, events);
+                        note_event = events.next_event();
+                    }
+                    Some(event) if (event.timing() as usize) < block_end => {
+                        block_end = event.timing() as usize;
+                        break 'events;
+                    }
+                    _ => break 'events,
+                }
+            }
+
+            // the demodulator is shared by every voice, so it follows the latest expression
+            let expression = self.voices.latest_expression();
+            let (cv_l, cv_r) = match sidechain {
+                Some((cv_l, cv_r)) => (cv_l, cv_r),
+                None => (&*buf_l, &*buf_r),
+            };
+            let amps = self.demodulator.submit_samples(
+                &cv_l[block_start..block_end],
+                &cv_r[block_start..block_end],
+                &settings.distribution_mode,
+                num_partials,
+                partial_offset,
+                &Transfer {
+                    floor: settings.floor
+                        + settings.expression_amount(&ExpressionTarget::Floor, &expression),
+                    ceiling: settings.ceiling
+                        + settings.expression_amount(&ExpressionTarget::Ceiling, &expression),
+                    bias: settings.bias
+                        + settings.expression_amount(&ExpressionTarget::Bias, &expression),
+                    curve: &settings.transfer_curve,
+                    table: &settings.transfer_table,
+                    decibel_min: settings.decibel_min,
+                    decibel_max: settings.decibel_max,
+                },
+                &settings.cv_layout,
+                settings.sync,
+            );
+            if let Some((mut left, right)) = amps {
+                // a new frame cuts the interpolation short, so the last one counts as reached
+                self.apply_pending();
+                let mut right = match settings.cv_layout {
+                    CvLayout::AmplitudePhase => {
+                        match settings.phase_mode {
+                            PhaseMode::Snap => self.pending_phases = Some(right),
+                            // a glide lasts a frame, so it lands with the interpolated
+                            // amplitudes, or a frame after the amplitudes step without
+                            // interpolation
+                            PhaseMode::Glide => self
+                                .voices
+                                .submit_phases(&right, self.demodulator.frame_size()),
+                        }
+                        left
+                    }
+                    CvLayout::AmplitudeRatio => {
+                        // partials the frame doesn't reach stay harmonic
+                        let reached = demodulator::frame_partials(
+                            self.demodulator.frame_size(),
+                            &settings.distribution_mode,
+                            num_partials,
+                            partial_offset,
+                        );
+                        self.pending_ratios = Some(std::array::from_fn(|n| {
+                            if reached.contains(&(n + 1)) {
+                                demodulator::decode_ratio(right[n])
+                            } else {
+                                (n + 1) as f64
+                            }
+                        }));
+                        left
+                    }
+                    _ => right,
+                };
+                // the frame is panned by its own ratios, even before the voices play them
+                Stereo {
+                    width: settings.stereo_width,
+                    pan_mode: &settings.pan_mode,
+                    pan_amount: settings.pan_amount,
+                }
+                .apply(
+                    &mut left,
+                    &mut right,
+                    self.pending_ratios.as_ref().unwrap_or(&self.ratios),
+                );
+                self.frames.push_frame(&left, &right);
+            }
+            let (amp_l, amp_r) = self.frames.advance(
+                block_end - block_start,
+                &settings.frame_interpolation,
+                budget,
+            );
+            let ramp_len = if self.interpolating {
+                block_end - block_start
+            } else {
+                0
+            };
+            self.voices.submit_amplitude_ramp(amp_l, amp_r, ramp_len);
+            if !self.interpolating || self.frames.frame_reached() {
+                self.apply_pending();
+            }
+
+            self.voices.set_envelope_times(
+                self.sample_rate,
+                settings.attack_ms,
+                settings.hold_ms,
+                settings.decay_ms,
+                settings.release_ms,
+            );
+            self.voices.set_envelope_shape(
+                settings.sustain,
+                &settings.attack_curve,
+                &settings.decay_curve,
+                &settings.release_curve,
+            );
+            self.voices.set_glide(
+                self.sample_rate,
+                settings.glide_ms,
+                &settings.glide_mode,
+                settings.glide_legato,
+            );
+            self.voices
+                .set_bend_ranges(settings.bend_range, settings.mpe_bend_range);
+            // soft notes tilt down, to 6 dB per octave at full sensitivity
+            self.voices.set_tilts(|voice| {
+                let velocity_tilt = voice
+                    .velocity_tilt_mod
+                    .map_or(settings.velocity_tilt, |m| m.value);
+                settings.expression_amount(&ExpressionTarget::Brightness, &voice.expression)
+                    - velocity_tilt * (1.0 - voice.velocity())
+            });
+            self.voices
+                .set_velocity_sensitivity(settings.velocity_sensitivity);
+            self.voices.set_unison(
+                settings.unison as usize,
+                settings.unison_detune,
+                &settings.unison_detune_curve,
+                settings.unison_spread,
+            );
+
+            self.dry_delay.process(
+                &mut buf_l[block_start..block_end],
+                &mut buf_r[block_start..block_end],
+            );
+            // in main input mode the main input is the CV itself, which is never passed through
+            let dry_level = if sidechain.is_some() {
+                settings.dry_level
+            } else {
+                0.0
+            };
+            for sample in buf_l[block_start..block_end]
+                .iter_mut()
+                .chain(buf_r[block_start..block_end].iter_mut())
+            {
+                *sample *= dry_level;
+            }
+
+            tune_partials(&self.ratios, settings, budget, &mut self.tuned_ratios);
+
+            self.voices.process(
+                self.sample_rate,
+                &self.tuning,
+                &self.tuned_ratios,
+                &mut buf_l[block_start..block_end],
+                &mut buf_r[block_start..block_end],
+                &settings.basic_gain_mode,
+                &self.slew,
+                &settings.synthesis_backend,
+                |voice| events.send_event(voice_terminated(block_start as u32, voice)),
+            );
+
+            block_start = block_end;
+            block_end = (block_start + BLOCK_SIZE).min(num_samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_accept_every_param() {
+        let params = SynthParams::default();
+        let mut settings = SynthSettings::from_params(&params);
+
+        for (id, param, _) in params.param_map() {
+            // SAFETY: the pointer is into `params`, which outlives the loop
+            let value = unsafe {
+                param.normalized_value_to_string(param.default_normalized_value(), false)
+            };
+            if let Err(err) = settings.set(&params, &id, &value) {
+                panic!("{id} = {value}: {err}");
+            }
+        }
+    }
+
+    #[test]
+    fn settings_reject_unknown_params() {
+        let params = SynthParams::default();
+        let mut settings = SynthSettings::from_params(&params);
+
+        for id in ["unknown", "transfer_point_0", "transfer_point_17"] {
+            assert!(settings.set(&params, id, "0").is_err(), "{id}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::TransferCurve;
+use nih_plug::util;
+
+/// Points in the user lookup table, evenly spaced over sample magnitudes `(0, 1]`.
+pub const TRANSFER_TABLE_POINTS: usize = 16;
+
+/// How CV sample values map to partial amplitudes.
+pub struct Transfer<'a> {
+    pub floor: f32,
+    pub ceiling: f32,
+    pub bias: f32,
+    pub curve: &'a TransferCurve,
+    pub table: &'a [f32; TRANSFER_TABLE_POINTS],
+    /// Levels the decibel curve maps just above the floor and at 1.
+    pub decibel_min: f32,
+    pub decibel_max: f32,
+}
+
+impl Transfer<'_> {
+    /// Decodes a sample after biasing it. Samples outside of `floor..=ceiling` are silent.
+    #[inline]
+    pub fn decode(&self, sample: f32) -> f32 {
+        let x = sample + self.bias;
+        if x < self.floor || x > self.ceiling {
+            return 0.0;
+        }
+
+        self.apply(x)
+    }
+
+    /// Every curve but the decibel one is odd-symmetric, so negative samples decode to
+    /// phase-inverted partials. The decibel curve spans `floor..=1` instead, with the floor
+    /// itself as silence and louder samples holding the top of the range.
+    fn apply(&self, x: f32) -> f32 {
+        match self.curve {
+            TransferCurve::Linear => x,
+            TransferCurve::SignedSquare => x * x * x.signum(),
+            TransferCurve::Cubic => x * x * x,
+            TransferCurve::Decibel => {
+                if x <= self.floor {
+                    return 0.0;
+                }
+
+                let t = if self.floor < 1.0 {
+                    ((x - self.floor) / (1.0 - self.floor)).min(1.0)
+                } else {
+                    1.0
+                };
+                util::db_to_gain(self.decibel_min + (self.decibel_max - self.decibel_min) * t)
+            }
+            TransferCurve::Table => self.lookup(x.abs()) * x.signum(),
+        }
+    }
+
+    /// Linear interpolation between table points, starting from silence at zero and holding the
+    /// last point above 1.
+    fn lookup(&self, magnitude: f32) -> f32 {
+        let position = (magnitude * TRANSFER_TABLE_POINTS as f32).min(TRANSFER_TABLE_POINTS as f32);
+        let index = position.floor() as usize;
+        if index >= TRANSFER_TABLE_POINTS {
+            return self.table[TRANSFER_TABLE_POINTS - 1];
+        }
+
+        let from = if index == 0 {
+            0.0
+        } else {
+            self.table[index - 1]
+        };
+        let to = self.table[index];
+        from + (to - from) * (position - index as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decibel(floor: f32, ceiling: f32) -> Transfer<'static> {
+        Transfer {
+            floor,
+            ceiling,
+            bias: 0.0,
+            curve: &TransferCurve::Decibel,
+            table: &[0.0; TRANSFER_TABLE_POINTS],
+            decibel_min: -96.0,
+            decibel_max: 0.0,
+        }
+    }
+
+    fn assert_db(gain: f32, db: f32) {
+        let decoded = util::gain_to_db(gain);
+        assert!(
+            (decoded - db).abs() < 1e-3,
+            "{decoded} dB, expected {db} dB"
+        );
+    }
+
+    #[test]
+    fn decibel_spans_floor_to_one() {
+        let transfer = decibel(0.0, 2.0);
+        assert_eq!(transfer.decode(0.0), 0.0);
+        assert_eq!(transfer.decode(-0.5), 0.0);
+        assert_db(transfer.decode(1e-6), -96.0);
+        assert_db(transfer.decode(0.5), -48.0);
+        assert_db(transfer.decode(1.0), 0.0);
+        assert_db(transfer.decode(1.5), 0.0);
+        assert_eq!(transfer.decode(2.5), 0.0);
+
+        let transfer = decibel(-1.0, 1.0);
+        assert_eq!(transfer.decode(-1.0), 0.0);
+        assert_db(transfer.decode(0.0), -48.0);
+        assert_db(transfer.decode(1.0), 0.0);
+    }
+
+    #[test]
+    fn odd_curves_invert_negative_samples() {
+        for curve in [
+            TransferCurve::Linear,
+            TransferCurve::SignedSquare,
+            TransferCurve::Cubic,
+            TransferCurve::Table,
+        ] {
+            let transfer = Transfer {
+                curve: &curve,
+                table: &std::array::from_fn(|i| (i + 1) as f32 / TRANSFER_TABLE_POINTS as f32),
+                ..decibel(-1.0, 1.0)
+            };
+            for x in [0.25, 0.5, 1.0] {
+                assert_eq!(transfer.decode(-x), -transfer.decode(x), "{curve:?} at {x}");
+            }
+        }
+    }
+}
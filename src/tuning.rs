@@ -0,0 +1,449 @@
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_REFERENCE_PITCH: f32 = 440.0;
+
+const MIDI_NOTES: usize = 128;
+
+/// The Scala scale and keyboard mapping files as loaded, kept whole in the plugin state so a
+/// session doesn't depend on the files staying where they were.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TuningFiles {
+    pub scl: Option<String>,
+    pub kbm: Option<String>,
+}
+
+impl TuningFiles {
+    /// Reads and checks the given files. Leaving either out falls back to 12-TET or to the
+    /// default mapping.
+    pub fn load(scl_path: Option<&str>, kbm_path: Option<&str>) -> Result<Self, String> {
+        let read =
+            |path: &str| std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"));
+        let files = Self {
+            scl: scl_path.map(read).transpose()?,
+            kbm: kbm_path.map(read).transpose()?,
+        };
+        Tuning::from_files(&files)?;
+        Ok(files)
+    }
+}
+
+/// Each MIDI note's frequency under a Scala scale and keyboard mapping. The default is 12-TET
+/// with A4 at the reference pitch.
+pub struct Tuning {
+    /// Base-2 log of each note's frequency. Unmapped notes are filled in between their mapped
+    /// neighbours, so bends and glides can pass over them.
+    log2_freqs: [f64; MIDI_NOTES],
+    mapped: [bool; MIDI_NOTES],
+    /// Scales every frequency by its ratio to 440 Hz, retuning a mapping's own reference
+    /// frequency along with it.
+    reference_pitch: f32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::from_files(&TuningFiles::default()).expect("12-TET is a valid tuning")
+    }
+}
+
+impl Tuning {
+    pub fn from_files(files: &TuningFiles) -> Result<Self, String> {
+        let scale = match &files.scl {
+            Some(scl) => Scale::parse(scl).map_err(|err| format!("scale: {err}"))?,
+            None => Scale::equal_temperament(),
+        };
+        let mapping = match &files.kbm {
+            Some(kbm) => KeyboardMapping::parse(kbm).map_err(|err| format!("mapping: {err}"))?,
+            None => KeyboardMapping::default(),
+        };
+
+        // the reference note may lie outside the mapped range, but not on an unmapped key
+        let reference_cents = mapping
+            .pattern_degree(mapping.reference_note)
+            .map(|degree| scale.cents(degree))
+            .ok_or("mapping: the reference note is not mapped")?;
+        let reference = mapping.reference_freq.log2();
+
+        let mut log2_freqs = [f64::NAN; MIDI_NOTES];
+        let mut mapped = [false; MIDI_NOTES];
+        for note in 0..MIDI_NOTES {
+            if let Some(degree) = mapping.degree(note as i32) {
+                log2_freqs[note] = reference + (scale.cents(degree) - reference_cents) / 1200.0;
+                mapped[note] = true;
+            }
+        }
+        if !mapped.contains(&true) {
+            return Err("mapping: no notes are mapped".to_string());
+        }
+        fill_unmapped(&mut log2_freqs, &mapped);
+
+        Ok(Self {
+            log2_freqs,
+            mapped,
+            reference_pitch: DEFAULT_REFERENCE_PITCH,
+        })
+    }
+
+    pub fn set_reference_pitch(&mut self, reference_pitch: f32) {
+        self.reference_pitch = reference_pitch;
+    }
+
+    pub fn is_mapped(&self, note: u8) -> bool {
+        self.mapped[note as usize]
+    }
+
+    /// The frequency for a fractional note number, e.g. a bent or gliding note. Between notes the
+    /// pitch moves evenly from one scale step to the next, and past either end of the keyboard
+    /// it keeps the outermost step size.
+    pub fn frequency(&self, note: f64) -> f64 {
+        let last = MIDI_NOTES - 1;
+        let index = (note.floor().max(0.0) as usize).min(last - 1);
+        let from = self.log2_freqs[index];
+        let to = self.log2_freqs[index + 1];
+        let log2_freq = from + (to - from) * (note - index as f64);
+
+        f64::exp2(log2_freq) * (self.reference_pitch / DEFAULT_REFERENCE_PITCH) as f64
+    }
+}
+
+/// Interpolates unmapped notes between the nearest mapped ones, holding the outermost mapped
+/// note's pitch past the ends.
+fn fill_unmapped(log2_freqs: &mut [f64; MIDI_NOTES], mapped: &[bool; MIDI_NOTES]) {
+    let mut previous: Option<usize> = None;
+    for note in 0..MIDI_NOTES {
+        if !mapped[note] {
+            continue;
+        }
+
+        match previous {
+            None => {
+                let pitch = log2_freqs[note];
+                log2_freqs[..note].fill(pitch);
+            }
+            Some(previous) => {
+                let (from, to) = (log2_freqs[previous], log2_freqs[note]);
+                let span = (note - previous) as f64;
+                for (step, between) in log2_freqs[previous + 1..note].iter_mut().enumerate() {
+                    *between = from + (to - from) * (step + 1) as f64 / span;
+                }
+            }
+        }
+        previous = Some(note);
+    }
+
+    if let Some(previous) = previous {
+        let pitch = log2_freqs[previous];
+        log2_freqs[previous + 1..].fill(pitch);
+    }
+}
+
+/// Non-comment lines of a Scala file, with anything after the first value dropped.
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.split_whitespace().next().unwrap_or(""))
+}
+
+/// A Scala `.scl` scale: the pitch of each degree above the first in cents, the last being the
+/// interval the scale repeats at.
+struct Scale {
+    cents: Vec<f64>,
+}
+
+impl Scale {
+    fn equal_temperament() -> Self {
+        Self {
+            cents: (1..=12).map(|step| step as f64 * 100.0).collect(),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        // the description line may be blank, so it is skipped before dropping trailing text
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+        lines.next().ok_or("missing description")?;
+        let mut lines = lines.map(|line| line.split_whitespace().next().unwrap_or(""));
+
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.parse().ok())
+            .ok_or("missing note count")?;
+        let cents = lines
+            .take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<_>, _>>()?;
+        if cents.len() < count {
+            return Err(format!("expected {count} notes, found {}", cents.len()));
+        }
+        if count == 0 {
+            return Err("the scale has no notes".to_string());
+        }
+
+        Ok(Self { cents })
+    }
+
+    fn cents(&self, degree: i32) -> f64 {
+        let len = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let step = degree.rem_euclid(len) as usize;
+        let base = if step == 0 { 0.0 } else { self.cents[step - 1] };
+        degree.div_euclid(len) as f64 * period + base
+    }
+}
+
+/// Pitches with a period are in cents, anything else is a ratio such as `3/2` or `2`.
+fn parse_pitch(pitch: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid pitch: {pitch}");
+    if pitch.contains('.') {
+        return pitch.parse().map_err(|_| invalid());
+    }
+
+    let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let numerator: f64 = numerator.parse().map_err(|_| invalid())?;
+    let denominator: f64 = denominator.parse().map_err(|_| invalid())?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(invalid());
+    }
+
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+/// A Scala `.kbm` keyboard mapping, which places scale degrees on MIDI notes.
+struct KeyboardMapping {
+    first_note: i32,
+    last_note: i32,
+    /// The note that plays the scale's first degree.
+    middle_note: i32,
+    reference_note: i32,
+    reference_freq: f64,
+    /// Degrees the mapping moves by each time it repeats.
+    octave_degree: i32,
+    /// Scale degree for each key in the repeating pattern, `None` leaving the key silent. An
+    /// empty map is linear, one degree per key.
+    map: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: MIDI_NOTES as i32 - 1,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: DEFAULT_REFERENCE_PITCH as f64,
+            octave_degree: 0,
+            map: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = scala_lines(text);
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .ok_or_else(|| format!("missing {name}"))
+                .map(str::to_string)
+        };
+        let integer = |name: &str, value: String| {
+            value
+                .parse::<i32>()
+                .map_err(|_| format!("invalid {name}: {value}"))
+        };
+
+        let size = integer("map size", field("map size")?)?;
+        let first_note = integer("first note", field("first note")?)?;
+        let last_note = integer("last note", field("last note")?)?;
+        let middle_note = integer("middle note", field("middle note")?)?;
+        let reference_note = integer("reference note", field("reference note")?)?;
+        let reference_freq = field("reference frequency")?;
+        let reference_freq: f64 = reference_freq
+            .parse()
+            .ok()
+            .filter(|freq: &f64| *freq > 0.0)
+            .ok_or_else(|| format!("invalid reference frequency: {reference_freq}"))?;
+        let octave_degree = integer("octave degree", field("octave degree")?)?;
+
+        // keys past the end of a short map are unmapped
+        let map = (0..size.max(0))
+            .map(|_| match field("map entry") {
+                Ok(key) if key == "x" => Ok(None),
+                Ok(key) => integer("map entry", key).map(Some),
+                Err(_) => Ok(None),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+            map,
+        })
+    }
+
+    fn degree(&self, note: i32) -> Option<i32> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+
+        self.pattern_degree(note)
+    }
+
+    fn pattern_degree(&self, note: i32) -> Option<i32> {
+        let offset = note - self.middle_note;
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+
+        let len = self.map.len() as i32;
+        self.map[offset.rem_euclid(len) as usize]
+            .map(|degree| offset.div_euclid(len) * self.octave_degree + degree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!(
+            (value - expected).abs() < 1e-6 * expected.abs().max(1.0),
+            "{value}, expected {expected}"
+        );
+    }
+
+    fn load(scl: Option<&str>, kbm: Option<&str>) -> Result<Tuning, String> {
+        Tuning::from_files(&TuningFiles {
+            scl: scl.map(str::to_string),
+            kbm: kbm.map(str::to_string),
+        })
+    }
+
+    /// A 12-key mapping with the middle and reference on note 60 at 261.63 Hz.
+    fn mapping(octave_degree: i32, keys: &str) -> String {
+        format!("! test.kbm\n12\n0\n127\n60\n60\n261.63\n{octave_degree}\n{keys}")
+    }
+
+    #[test]
+    fn scale_skips_comments() {
+        let scale = Scale::parse(
+            "! fifths.scl\n!\nA scale of fifths\n! the count\n 2\n!\n 3/2 fifth\n 2/1\n",
+        )
+        .unwrap();
+        assert_eq!(scale.cents.len(), 2);
+        assert_close(scale.cents[0], 1200.0 * 1.5f64.log2());
+        assert_close(scale.cents[1], 1200.0);
+
+        // the description may be blank
+        let scale = Scale::parse("! blank.scl\n\n1\n1200.0\n").unwrap();
+        assert_eq!(scale.cents, [1200.0]);
+    }
+
+    #[test]
+    fn scale_reads_cents_and_ratios() {
+        let scale = Scale::parse("cents and ratios\n5\n100.0\n250.\n5/4\n3\n2\n").unwrap();
+        let expected = [
+            100.0,
+            250.0,
+            1200.0 * 1.25f64.log2(),
+            1200.0 * 3f64.log2(),
+            1200.0,
+        ];
+        for (cents, expected) in scale.cents.iter().zip(expected) {
+            assert_close(*cents, expected);
+        }
+
+        // degrees past the period repeat it
+        assert_close(scale.cents(6), 1300.0);
+        assert_close(scale.cents(-1), expected[3] - 1200.0);
+        assert_close(scale.cents(-5), -1200.0);
+    }
+
+    #[test]
+    fn scale_rejects_invalid_pitches() {
+        for scl in [
+            "bad\n1\nfifth\n",
+            "bad\n1\n0/1\n",
+            "bad\n1\n-3/2\n",
+            "short\n3\n100.0\n200.0\n",
+            "empty\n0\n",
+            "no count\n",
+        ] {
+            assert!(Scale::parse(scl).is_err(), "{scl:?}");
+        }
+    }
+
+    #[test]
+    fn linear_mapping() {
+        let mapping =
+            KeyboardMapping::parse("! linear.kbm\n0\n0\n127\n60\n69\n440.0\n0\n").unwrap();
+        assert!(mapping.map.is_empty());
+        assert_eq!(mapping.degree(60), Some(0));
+        assert_eq!(mapping.degree(72), Some(12));
+        assert_eq!(mapping.degree(0), Some(-60));
+
+        let tuning = load(None, Some("0\n0\n127\n60\n69\n440.0\n0\n")).unwrap();
+        assert_close(tuning.frequency(69.0), 440.0);
+        assert_close(tuning.frequency(81.0), 880.0);
+    }
+
+    #[test]
+    fn default_tuning_is_equal_temperament() {
+        let mut tuning = Tuning::default();
+        assert_close(tuning.frequency(69.0), 440.0);
+        assert_close(tuning.frequency(60.0), 440.0 * f64::exp2(-9.0 / 12.0));
+        assert_close(tuning.frequency(69.5), 440.0 * f64::exp2(0.5 / 12.0));
+
+        tuning.set_reference_pitch(432.0);
+        assert_close(tuning.frequency(69.0), 432.0);
+    }
+
+    #[test]
+    fn unmapped_keys() {
+        // a pentatonic scale on the white keys, with F and B left out
+        let keys = "0\nx\n1\nx\n2\nx\nx\n3\nx\n4\nx\nx\n";
+        let scl = "pentatonic\n5\n200.0\n400.0\n700.0\n900.0\n1200.0\n";
+        let tuning = load(Some(scl), Some(&mapping(5, keys))).unwrap();
+
+        assert!(tuning.is_mapped(60));
+        assert!(!tuning.is_mapped(61));
+        assert!(tuning.is_mapped(72));
+        assert!(!tuning.is_mapped(71));
+        assert_close(tuning.frequency(60.0), 261.63);
+        assert_close(tuning.frequency(62.0), 261.63 * f64::exp2(200.0 / 1200.0));
+        assert_close(tuning.frequency(72.0), 261.63 * 2.0);
+
+        // unmapped keys sit between their mapped neighbours
+        assert_close(tuning.frequency(61.0), 261.63 * f64::exp2(100.0 / 1200.0));
+
+        // keys past the end of a short map are unmapped too
+        let tuning = load(None, Some(&mapping(12, "0\n1\n"))).unwrap();
+        assert!(tuning.is_mapped(60));
+        assert!(tuning.is_mapped(61));
+        assert!(!tuning.is_mapped(62));
+        assert!(tuning.is_mapped(72));
+    }
+
+    #[test]
+    fn unmapped_reference_note_is_an_error() {
+        let kbm = "12\n0\n127\n60\n61\n277.18\n12\n0\nx\n";
+        assert!(load(None, Some(kbm)).is_err());
+    }
+
+    #[test]
+    fn extrapolates_past_the_keyboard() {
+        let tuning = Tuning::default();
+        assert_close(tuning.frequency(-1.0), 440.0 * f64::exp2(-70.0 / 12.0));
+        assert_close(tuning.frequency(-12.0), 440.0 * f64::exp2(-81.0 / 12.0));
+        assert_close(tuning.frequency(130.0), 440.0 * f64::exp2(61.0 / 12.0));
+
+        // a mapping that stops short holds its outermost pitch, then keeps its last step
+        let tuning = load(None, Some("0\n48\n72\n60\n69\n440.0\n0\n")).unwrap();
+        assert!(!tuning.is_mapped(47));
+        assert!(!tuning.is_mapped(73));
+        assert_close(tuning.frequency(30.0), tuning.frequency(48.0));
+        assert_close(tuning.frequency(100.0), tuning.frequency(72.0));
+    }
+}
@@ -1,28 +1,141 @@
 use crate::{
-    additive_engine::{AdditiveEngine, MAX_HARMONICS},
-    envelope::AREnvelope,
-    BasicGainMode,
+    additive_engine::{self, AdditiveEngine, MAX_HARMONICS},
+    envelope::ADSREnvelope,
+    slew_limiter::SlewLimiter,
+    tuning::Tuning,
+    BasicGainMode, DetuneCurve, SynthesisBackend,
 };
 
-const BEND_RANGE: f64 = 12.0;
+pub const DEFAULT_BEND_RANGE: f32 = 12.0;
+pub const DEFAULT_MPE_BEND_RANGE: f32 = 48.0;
 const VOICE_BLOCK_SIZE: usize = 32;
+pub const MAX_UNISON: usize = 8;
+
+/// Pitch bend, pressure and timbre for a note, as normalized MIDI values.
+#[derive(Clone, Copy)]
+pub struct Expression {
+    /// Centred at 0.5.
+    pub bend: f32,
+    pub pressure: f32,
+    /// Centred at 0.5, like MPE's default CC 74 of 64.
+    pub timbre: f32,
+}
+
+impl Default for Expression {
+    fn default() -> Self {
+        Self {
+            bend: 0.5,
+            pressure: 0.0,
+            timbre: 0.5,
+        }
+    }
+}
+
+/// A parameter's value for a single voice under polyphonic modulation.
+#[derive(Clone, Copy)]
+pub struct VoiceModulation {
+    pub normalized_offset: f32,
+    pub value: f32,
+}
+
+/// Pseudo-random phase in cycles for partial `partial` of a unison copy, so copies don't start
+/// out cancelling or reinforcing each other.
+fn random_phase(seed: u32, partial: usize) -> f64 {
+    // a small integer hash, good enough to decorrelate phases
+    let mut x = seed ^ (partial as u32).wrapping_mul(0x9e37_79b9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x as f64 / u32::MAX as f64
+}
+
+/// Each copy's position across the unison spread, from -1 to 1.
+fn unison_position(copy: usize, count: usize, curve: &DetuneCurve) -> f32 {
+    if count < 2 {
+        return 0.0;
+    }
+
+    let x = copy as f32 / (count - 1) as f32 * 2.0 - 1.0;
+    match curve {
+        DetuneCurve::Linear => x,
+        DetuneCurve::Clustered => x * x.abs(),
+        DetuneCurve::Wide => x.abs().sqrt() * x.signum(),
+    }
+}
 
 pub struct AdditiveVoice {
-    pub engine: AdditiveEngine,
-    pub envelope: AREnvelope,
+    /// One engine per unison copy, all fed the same spectrum. Only the first `unison` sound, and
+    /// only those are kept up to date.
+    engines: Vec<AdditiveEngine>,
+    unison: usize,
+    /// Each copy's detune in semitones, and its gain in each channel.
+    unison_detune: [f32; MAX_UNISON],
+    unison_gain_l: [f32; MAX_UNISON],
+    unison_gain_r: [f32; MAX_UNISON],
+    /// Advanced on every phase reset, so each note's copies start from new phases.
+    phase_seed: u32,
+    pub envelope: ADSREnvelope,
+    pub attack_mod: Option<VoiceModulation>,
+    pub hold_mod: Option<VoiceModulation>,
+    pub decay_mod: Option<VoiceModulation>,
+    pub sustain_mod: Option<VoiceModulation>,
+    pub release_mod: Option<VoiceModulation>,
+    pub unison_detune_mod: Option<VoiceModulation>,
+    pub velocity_tilt_mod: Option<VoiceModulation>,
+    pub glide_mod: Option<VoiceModulation>,
+    voice_id: i32,
+    channel: u8,
     current_midi_note: u8,
-    bend_value: f32,
-    notes_on: usize,
+    /// The sounding note number, which trails `current_midi_note` while gliding.
+    pitch: f32,
+    /// The interval the current glide started out covering, and the semitones per sample that
+    /// `pitch` moves towards `current_midi_note`.
+    glide_interval: f32,
+    glide_step: f32,
+    pub expression: Expression,
+    velocity: f32,
+    /// Output gain from velocity, set from the velocity sensitivity.
+    pub velocity_gain: f32,
+    /// Bend from the MPE master channel, shared by every note.
+    pub master_bend: f32,
+    bend_range: f32,
+    master_bend_range: f32,
+    gate: bool,
 }
 
 impl Default for AdditiveVoice {
     fn default() -> Self {
         let mut this = Self {
-            engine: Default::default(),
+            engines: (0..MAX_UNISON).map(|_| AdditiveEngine::default()).collect(),
+            unison: 1,
+            unison_detune: [0.0; MAX_UNISON],
+            unison_gain_l: [1.0; MAX_UNISON],
+            unison_gain_r: [1.0; MAX_UNISON],
+            phase_seed: 0,
             envelope: Default::default(),
+            attack_mod: None,
+            hold_mod: None,
+            decay_mod: None,
+            sustain_mod: None,
+            release_mod: None,
+            unison_detune_mod: None,
+            velocity_tilt_mod: None,
+            glide_mod: None,
+            voice_id: 0,
+            channel: 0,
             current_midi_note: 0,
-            bend_value: 0.5,
-            notes_on: 0,
+            pitch: 0.0,
+            glide_interval: 0.0,
+            glide_step: 0.0,
+            expression: Expression::default(),
+            velocity: 1.0,
+            velocity_gain: 1.0,
+            master_bend: 0.5,
+            bend_range: DEFAULT_BEND_RANGE,
+            master_bend_range: 0.0,
+            gate: false,
         };
         this.reset_phases();
         this
@@ -30,46 +143,185 @@ impl Default for AdditiveVoice {
 }
 
 impl AdditiveVoice {
+    /// Starts the first copy from the usual initial phases, and the rest from those plus a random
+    /// offset.
     fn reset_phases(&mut self) {
-        for (i, phi) in self.engine.phases.iter_mut().enumerate() {
-            *phi = MAX_HARMONICS as f64 / (i + 1) as f64;
+        for (copy, engine) in self.engines.iter_mut().enumerate() {
+            let seed = self
+                .phase_seed
+                .wrapping_add(copy as u32)
+                .wrapping_mul(0x2545_f491);
+            for (i, phi) in engine.phases.iter_mut().enumerate() {
+                *phi = additive_engine::initial_phase(i);
+                if copy > 0 {
+                    *phi += random_phase(seed, i);
+                }
+            }
+            engine.reset_phase_offsets();
         }
+        self.phase_seed = self.phase_seed.wrapping_add(MAX_UNISON as u32);
+    }
+
+    pub fn voice_id(&self) -> i32 {
+        self.voice_id
     }
 
-    pub fn note_on(&mut self, note: u8) {
-        if self.notes_on == 0 {
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn note(&self) -> u8 {
+        self.current_midi_note
+    }
+
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    pub fn is_gated(&self) -> bool {
+        self.gate
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.gate || self.envelope.is_releasing()
+    }
+
+    /// Starts a note from its channel's current expression.
+    pub fn note_on(
+        &mut self,
+        voice_id: i32,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+        expression: Expression,
+    ) {
+        if !self.is_active() {
             self.envelope.reset();
         }
+        self.envelope.retrigger();
+        self.voice_id = voice_id;
+        self.channel = channel;
         self.current_midi_note = note;
-        self.notes_on += 1;
+        self.pitch = note as f32;
+        self.glide_interval = 0.0;
+        self.glide_step = 0.0;
+        self.velocity = velocity;
+        self.expression = expression;
+        self.attack_mod = None;
+        self.hold_mod = None;
+        self.decay_mod = None;
+        self.sustain_mod = None;
+        self.release_mod = None;
+        self.unison_detune_mod = None;
+        self.velocity_tilt_mod = None;
+        self.glide_mod = None;
+        self.gate = true;
+    }
+
+    /// Slides the note in from `from`, at the speed set by [`Self::set_glide_time`].
+    pub fn glide_from(&mut self, from: f32) {
+        self.pitch = from;
+        self.glide_interval = (self.current_midi_note as f32 - from).abs();
+    }
+
+    /// Sets how long the glide takes: `samples` for its whole interval, or per octave at a
+    /// constant rate. Does nothing once the glide has finished.
+    pub fn set_glide_time(&mut self, samples: f32, constant_rate: bool) {
+        if self.pitch == self.current_midi_note as f32 {
+            return;
+        }
+
+        let interval = if constant_rate {
+            12.0
+        } else {
+            self.glide_interval
+        };
+        self.glide_step = interval / samples.max(1.0);
+    }
+
+    fn advance_glide(&mut self, samples: usize) {
+        let target = self.current_midi_note as f32;
+        let step = self.glide_step * samples as f32;
+        if (target - self.pitch).abs() <= step {
+            self.pitch = target;
+            self.glide_step = 0.0;
+        } else {
+            self.pitch += step.copysign(target - self.pitch);
+        }
     }
 
     pub fn note_off(&mut self) {
-        self.notes_on = self.notes_on.saturating_sub(1);
-        if self.notes_on == 0 {
-            self.envelope.start_release();
+        self.gate = false;
+        self.envelope.start_release();
+    }
+
+    /// Sets the bend ranges in semitones for the note's own bend and for the master bend.
+    pub fn set_bend_ranges(&mut self, bend_range: f32, master_bend_range: f32) {
+        self.bend_range = bend_range;
+        self.master_bend_range = master_bend_range;
+    }
+
+    /// Spreads `count` copies over `detune` semitones, and pans them up to `spread` either side.
+    /// Panning only turns one channel down, so each copy keeps the demodulated left and right
+    /// spectra on their own sides.
+    pub fn set_unison(&mut self, count: usize, detune: f32, curve: &DetuneCurve, spread: f32) {
+        let count = count.clamp(1, MAX_UNISON);
+        if count > self.unison {
+            // the added copies have missed every update since they last sounded
+            let (first, rest) = self.engines.split_at_mut(1);
+            for engine in &mut rest[self.unison - 1..count - 1] {
+                engine.follow(&first[0]);
+            }
+        }
+        self.unison = count;
+
+        let level = 1.0 / (self.unison as f32).sqrt();
+        for copy in 0..self.unison {
+            let position = unison_position(copy, self.unison, curve);
+            let pan = position * spread;
+            self.unison_detune[copy] = position * detune * 0.5;
+            self.unison_gain_l[copy] = (1.0 - pan).min(1.0) * level;
+            self.unison_gain_r[copy] = (1.0 + pan).min(1.0) * level;
         }
     }
 
-    pub fn midi_pitch_bend(&mut self, value: f32) {
-        self.bend_value = value;
+    /// The engines of the sounding unison copies.
+    pub fn unison_engines_mut(&mut self) -> &mut [AdditiveEngine] {
+        &mut self.engines[..self.unison]
+    }
+
+    fn reset_engines(&mut self) {
+        for engine in &mut self.engines {
+            engine.reset_slew_tracking();
+            engine.reset_resynthesis();
+        }
     }
 
     pub fn reset(&mut self) {
         self.envelope.reset();
-        self.notes_on = 0;
-        self.engine.reset_slew_tracking();
+        self.gate = false;
+        self.reset_engines();
     }
 
+    /// Renders the voice with each partial at `ratios` times the fundamental, which `tuning`
+    /// takes from the note.
+    #[allow(clippy::too_many_arguments)]
     pub fn process(
         &mut self,
         sample_rate: f32,
+        tuning: &Tuning,
+        ratios: &[f64; MAX_HARMONICS],
         out_l: &mut [f32],
         out_r: &mut [f32],
         basic_gain_mode: &BasicGainMode,
-        slew_limiting: bool,
+        slew: &SlewLimiter,
+        backend: &SynthesisBackend,
     ) {
-        if !(self.notes_on > 0 || self.envelope.is_releasing()) {
+        if !self.is_active() {
             return;
         }
 
@@ -79,13 +331,9 @@ impl AdditiveVoice {
             "channel output buffers must match length"
         );
 
-        let note = self.current_midi_note as f64
-            + (self.bend_value.clamp(0.0, 1.0) * 2.0 - 1.0) as f64 * BEND_RANGE;
-        let fundamental = 2.0f64.powf((note - 69.0) / 12.0) * 440.0;
+        let bend = (self.expression.bend.clamp(0.0, 1.0) * 2.0 - 1.0) * self.bend_range
+            + (self.master_bend.clamp(0.0, 1.0) * 2.0 - 1.0) * self.master_bend_range;
         let mut i_freqs = [0.0; MAX_HARMONICS];
-        for n in 0..MAX_HARMONICS {
-            i_freqs[n] = fundamental * (n + 1) as f64;
-        }
 
         let mut i = 0;
         while i < out_l.len() {
@@ -99,26 +347,122 @@ impl AdditiveVoice {
             let buf_r = &mut buf_r[0..block_len];
 
             self.envelope.next_block(envelope_values, block_len);
-            self.engine.generate_samples(
-                &i_freqs,
-                sample_rate,
-                buf_l,
-                buf_r,
-                basic_gain_mode,
-                slew_limiting,
-            );
+            for copy in 0..self.unison {
+                // recomputed every block, so glides move smoothly
+                let note = self.pitch as f64 + (bend + self.unison_detune[copy]) as f64;
+                let fundamental = tuning.frequency(note);
+                let budget = self.engines[copy].partial_budget();
+                for (freq, ratio) in i_freqs[..budget].iter_mut().zip(ratios) {
+                    *freq = fundamental * ratio;
+                }
+
+                let mut copy_l = [0.0; VOICE_BLOCK_SIZE];
+                let mut copy_r = [0.0; VOICE_BLOCK_SIZE];
+                let copy_l = &mut copy_l[0..block_len];
+                let copy_r = &mut copy_r[0..block_len];
+                self.engines[copy].generate_samples(
+                    &i_freqs,
+                    sample_rate,
+                    copy_l,
+                    copy_r,
+                    basic_gain_mode,
+                    slew,
+                    backend,
+                );
+
+                let (gain_l, gain_r) = (self.unison_gain_l[copy], self.unison_gain_r[copy]);
+                for smp in 0..block_len {
+                    buf_l[smp] += copy_l[smp] * gain_l;
+                    buf_r[smp] += copy_r[smp] * gain_r;
+                }
+            }
 
             for smp in 0..block_len {
-                out_l[i + smp] += buf_l[smp] * envelope_values[smp];
-                out_r[i + smp] += buf_r[smp] * envelope_values[smp];
+                let gain = envelope_values[smp] * self.velocity_gain;
+                out_l[i + smp] += buf_l[smp] * gain;
+                out_r[i + smp] += buf_r[smp] * gain;
             }
 
+            self.advance_glide(block_len);
             i += block_len;
         }
 
-        if self.notes_on == 0 && !self.envelope.is_releasing() {
+        // a decay to a sustain level of 0 ends the note without a release
+        if self.envelope.is_idle() {
+            self.gate = false;
+        }
+        if !self.is_active() {
             self.reset_phases();
-            self.engine.reset_slew_tracking();
+            self.reset_engines();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_unison_copies_follow_the_first() {
+        let mut voice = AdditiveVoice::default();
+        voice.set_unison(1, 0.0, &DetuneCurve::Linear, 0.0);
+
+        let amp_l = std::array::from_fn(|i| 1.0 / (i + 1) as f32);
+        let amp_r = [0.25; MAX_HARMONICS];
+        for engine in voice.unison_engines_mut() {
+            engine.submit_amplitudes(&amp_l, &amp_r);
         }
+        assert_eq!(voice.unison_engines_mut().len(), 1);
+
+        voice.set_unison(3, 0.0, &DetuneCurve::Linear, 0.0);
+        let engines = voice.unison_engines_mut();
+        assert_eq!(engines.len(), 3);
+        for engine in &engines[1..] {
+            assert_eq!(engine.amp_l, amp_l);
+            assert_eq!(engine.amp_r, amp_r);
+        }
+    }
+
+    #[test]
+    fn glides_take_the_glide_time_for_any_interval() {
+        let mut voice = AdditiveVoice::default();
+        voice.note_on(1, 0, 64, 1.0, Expression::default());
+        voice.glide_from(60.0);
+        voice.set_glide_time(100.0, false);
+
+        voice.advance_glide(50);
+        assert_eq!(voice.pitch(), 62.0);
+        // a new time applies to the rest of the glide
+        voice.set_glide_time(200.0, false);
+        voice.advance_glide(50);
+        assert_eq!(voice.pitch(), 63.0);
+        voice.advance_glide(100);
+        assert_eq!(voice.pitch(), 64.0);
+    }
+
+    #[test]
+    fn decaying_to_no_sustain_ends_the_note() {
+        let mut voice = AdditiveVoice::default();
+        voice.envelope.set_attack_time(1000.0, 1.0);
+        voice.envelope.set_hold_time(1000.0, 0.0);
+        voice.envelope.set_decay_time(1000.0, 10.0);
+        voice.envelope.set_sustain_level(0.0);
+        voice.note_on(1, 0, 60, 1.0, Expression::default());
+
+        let ratios = std::array::from_fn(|n| (n + 1) as f64);
+        let mut out_l = [0.0; 64];
+        let mut out_r = [0.0; 64];
+        voice.process(
+            1000.0,
+            &Tuning::default(),
+            &ratios,
+            &mut out_l,
+            &mut out_r,
+            &BasicGainMode::Flat,
+            &SlewLimiter::default(),
+            &SynthesisBackend::OscillatorBank,
+        );
+        assert!(!voice.is_gated());
+        assert!(!voice.is_active());
     }
 }
@@ -0,0 +1,592 @@
+use crate::{
+    additive_engine::MAX_HARMONICS,
+    slew_limiter::SlewLimiter,
+    tuning::Tuning,
+    voice::{AdditiveVoice, Expression},
+    BasicGainMode, DetuneCurve, EnvelopeCurve, GlideMode, SynthesisBackend, VoiceStealing,
+};
+
+pub const MAX_POLYPHONY: usize = 16;
+const MIDI_CHANNELS: usize = 16;
+/// The lower zone's master channel, whose pitch bend applies to every note in MPE mode.
+const MPE_MASTER_CHANNEL: u8 = 0;
+
+/// Voice ID for notes from hosts that don't provide one.
+pub fn fallback_voice_id(note: u8, channel: u8) -> i32 {
+    note as i32 | ((channel as i32) << 16)
+}
+
+pub struct VoicePool {
+    voices: Vec<AdditiveVoice>,
+    ages: [u64; MAX_POLYPHONY],
+    next_age: u64,
+    mpe: bool,
+    /// Expression as last received on each channel in MPE mode, or on any channel in the first
+    /// slot otherwise, so new notes start from it.
+    channel_expression: [Expression; MIDI_CHANNELS],
+    master_bend: f32,
+    latest_expression: Expression,
+    /// The voice that started most recently, which new notes glide from.
+    last_voice: Option<usize>,
+    glide_samples: f32,
+    glide_constant_rate: bool,
+    glide_legato_only: bool,
+    /// The latest spectrum and phases, which only sounding voices are kept up to date with, for
+    /// new notes to start from.
+    amp_l: [f32; MAX_HARMONICS],
+    amp_r: [f32; MAX_HARMONICS],
+    phases: Option<[f32; MAX_HARMONICS]>,
+}
+
+impl Default for VoicePool {
+    fn default() -> Self {
+        let mut voices = Vec::with_capacity(MAX_POLYPHONY);
+        voices.resize_with(MAX_POLYPHONY, AdditiveVoice::default);
+
+        Self {
+            voices,
+            ages: [0; MAX_POLYPHONY],
+            next_age: 0,
+            mpe: false,
+            channel_expression: [Expression::default(); MIDI_CHANNELS],
+            master_bend: 0.5,
+            latest_expression: Expression::default(),
+            last_voice: None,
+            glide_samples: 0.0,
+            glide_constant_rate: false,
+            glide_legato_only: false,
+            amp_l: [0.0; MAX_HARMONICS],
+            amp_r: [0.0; MAX_HARMONICS],
+            phases: None,
+        }
+    }
+}
+
+impl VoicePool {
+    pub fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+        self.ages.fill(0);
+        self.next_age = 0;
+        self.last_voice = None;
+        self.reset_expression();
+    }
+
+    fn reset_expression(&mut self) {
+        self.channel_expression.fill(Expression::default());
+        self.master_bend = 0.5;
+        self.latest_expression = Expression::default();
+        for voice in &mut self.voices {
+            voice.expression = Expression::default();
+            voice.master_bend = 0.5;
+        }
+    }
+
+    /// Switches between per-channel MPE expression and channel-wide expression for all notes.
+    pub fn set_mpe(&mut self, mpe: bool) {
+        if mpe != self.mpe {
+            self.mpe = mpe;
+            self.reset_expression();
+        }
+    }
+
+    /// The most recently received expression from any note or channel.
+    pub fn latest_expression(&self) -> Expression {
+        self.latest_expression
+    }
+
+    /// Whether every voice has finished, release included.
+    pub fn is_idle(&self) -> bool {
+        !self.voices.iter().any(|voice| voice.is_active())
+    }
+
+    pub fn active_voices_mut(&mut self) -> impl Iterator<Item = &mut AdditiveVoice> {
+        self.voices.iter_mut().filter(|voice| voice.is_active())
+    }
+
+    pub fn voice_mut(&mut self, voice_id: i32) -> Option<&mut AdditiveVoice> {
+        self.active_voices_mut()
+            .find(|voice| voice.voice_id() == voice_id)
+    }
+
+    /// Shares a demodulated spectrum with every sounding voice.
+    pub fn submit_amplitudes(
+        &mut self,
+        amp_l: &[f32; MAX_HARMONICS],
+        amp_r: &[f32; MAX_HARMONICS],
+    ) {
+        self.amp_l.copy_from_slice(amp_l);
+        self.amp_r.copy_from_slice(amp_r);
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_active()) {
+            for engine in voice.unison_engines_mut() {
+                engine.submit_amplitudes(amp_l, amp_r);
+            }
+        }
+    }
+
+    pub fn submit_phases(&mut self, phases: &[f32; MAX_HARMONICS], glide_len: usize) {
+        self.phases = Some(*phases);
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_active()) {
+            for engine in voice.unison_engines_mut() {
+                engine.submit_phases(phases, glide_len);
+            }
+        }
+    }
+
+    pub fn submit_amplitude_ramp(
+        &mut self,
+        amp_l: &[f32; MAX_HARMONICS],
+        amp_r: &[f32; MAX_HARMONICS],
+        len: usize,
+    ) {
+        self.amp_l.copy_from_slice(amp_l);
+        self.amp_r.copy_from_slice(amp_r);
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_active()) {
+            for engine in voice.unison_engines_mut() {
+                engine.submit_amplitude_ramp(amp_l, amp_r, len);
+            }
+        }
+    }
+
+    /// Updates envelope times, preferring each voice's modulated times where it has them.
+    pub fn set_envelope_times(
+        &mut self,
+        sample_rate: f32,
+        attack_ms: f32,
+        hold_ms: f32,
+        decay_ms: f32,
+        release_ms: f32,
+    ) {
+        for voice in &mut self.voices {
+            let attack_ms = voice.attack_mod.map_or(attack_ms, |m| m.value);
+            let hold_ms = voice.hold_mod.map_or(hold_ms, |m| m.value);
+            let decay_ms = voice.decay_mod.map_or(decay_ms, |m| m.value);
+            let release_ms = voice.release_mod.map_or(release_ms, |m| m.value);
+            voice.envelope.set_attack_time(sample_rate, attack_ms);
+            voice.envelope.set_hold_time(sample_rate, hold_ms);
+            voice.envelope.set_decay_time(sample_rate, decay_ms);
+            voice.envelope.set_release_time(sample_rate, release_ms);
+        }
+    }
+
+    /// Updates the sustain level, preferring each voice's modulated level, and the curves.
+    pub fn set_envelope_shape(
+        &mut self,
+        sustain_level: f32,
+        attack_curve: &EnvelopeCurve,
+        decay_curve: &EnvelopeCurve,
+        release_curve: &EnvelopeCurve,
+    ) {
+        for voice in &mut self.voices {
+            let sustain_level = voice.sustain_mod.map_or(sustain_level, |m| m.value);
+            voice.envelope.set_sustain_level(sustain_level);
+            voice
+                .envelope
+                .set_curves(attack_curve, decay_curve, release_curve);
+        }
+    }
+
+    /// Sets pitch bend ranges in semitones. In MPE mode, notes bend by `mpe_bend_range` and the
+    /// master channel by `bend_range`; otherwise everything bends by `bend_range`.
+    pub fn set_bend_ranges(&mut self, bend_range: f32, mpe_bend_range: f32) {
+        let (note_range, master_range) = if self.mpe {
+            (mpe_bend_range, bend_range)
+        } else {
+            (bend_range, 0.0)
+        };
+        for voice in &mut self.voices {
+            voice.set_bend_ranges(note_range, master_range);
+        }
+    }
+
+    /// Sets the portamento. In constant rate mode, `glide_ms` is the time per octave; otherwise
+    /// it is the time for any interval. Zero disables glide for notes that start from now on.
+    /// Glides under way follow the new time, or their voice's modulated time where it has one.
+    pub fn set_glide(
+        &mut self,
+        sample_rate: f32,
+        glide_ms: f32,
+        mode: &GlideMode,
+        legato_only: bool,
+    ) {
+        self.glide_samples = glide_ms * sample_rate / 1000.0;
+        self.glide_constant_rate = *mode == GlideMode::ConstantRate;
+        self.glide_legato_only = legato_only;
+
+        for voice in &mut self.voices {
+            let glide_ms = voice.glide_mod.map_or(glide_ms, |m| m.value);
+            voice.set_glide_time(glide_ms * sample_rate / 1000.0, self.glide_constant_rate);
+        }
+    }
+
+    /// Sets every voice's spectral tilt from its expression and velocity.
+    pub fn set_tilts(&mut self, tilt: impl Fn(&AdditiveVoice) -> f32) {
+        for voice in &mut self.voices {
+            let tilt = tilt(voice);
+            for engine in voice.unison_engines_mut() {
+                engine.set_tilt(tilt);
+            }
+        }
+    }
+
+    pub fn set_partial_budget(&mut self, budget: usize) {
+        for voice in &mut self.voices {
+            for engine in voice.unison_engines_mut() {
+                engine.set_partial_budget(budget);
+            }
+        }
+    }
+
+    /// Spreads each voice's copies over `detune_cents`, or over its own modulated detune.
+    pub fn set_unison(
+        &mut self,
+        count: usize,
+        detune_cents: f32,
+        curve: &DetuneCurve,
+        spread: f32,
+    ) {
+        for voice in &mut self.voices {
+            let detune_cents = voice.unison_detune_mod.map_or(detune_cents, |m| m.value);
+            voice.set_unison(count, detune_cents / 100.0, curve, spread);
+        }
+    }
+
+    /// Scales each voice by its velocity, from full level at a sensitivity of 0 to silent at
+    /// velocity 0 and a sensitivity of 1.
+    pub fn set_velocity_sensitivity(&mut self, sensitivity: f32) {
+        for voice in &mut self.voices {
+            voice.velocity_gain = 1.0 - sensitivity * (1.0 - voice.velocity());
+        }
+    }
+
+    /// Starts a note, calling `on_terminated` with the voice it replaces if that was still
+    /// sounding.
+    #[allow(clippy::too_many_arguments)]
+    pub fn note_on(
+        &mut self,
+        voice_id: i32,
+        channel: u8,
+        note: u8,
+        velocity: f32,
+        polyphony: usize,
+        stealing: &VoiceStealing,
+        mut on_terminated: impl FnMut(&AdditiveVoice),
+    ) {
+        // legato means another note is still held when this one starts
+        let legato = self.voices.iter().any(|voice| voice.is_gated());
+        let glide_from = self
+            .last_voice
+            .map(|idx| self.voices[idx].pitch())
+            .filter(|_| self.glide_samples >= 1.0 && (legato || !self.glide_legato_only));
+
+        let idx = self.allocate(note, polyphony.clamp(1, MAX_POLYPHONY), stealing);
+        let expression = self.channel_expression[self.expression_slot(channel)];
+        let master_bend = self.master_bend;
+
+        let voice = &mut self.voices[idx];
+        if voice.is_active() {
+            on_terminated(voice);
+        }
+        voice.note_on(voice_id, channel, note, velocity, expression);
+        voice.master_bend = master_bend;
+        // the voice wasn't being updated while it was silent
+        for engine in voice.unison_engines_mut() {
+            engine.submit_amplitudes(&self.amp_l, &self.amp_r);
+            if let Some(phases) = &self.phases {
+                engine.submit_phases(phases, 0);
+            }
+        }
+        if let Some(from) = glide_from {
+            voice.glide_from(from);
+            voice.set_glide_time(self.glide_samples, self.glide_constant_rate);
+        }
+        self.last_voice = Some(idx);
+        self.ages[idx] = self.next_age;
+        self.next_age += 1;
+    }
+
+    pub fn note_off(&mut self, voice_id: Option<i32>, channel: u8, note: u8) {
+        for voice in &mut self.voices {
+            let matches = match voice_id {
+                Some(voice_id) => voice.voice_id() == voice_id,
+                None => voice.channel() == channel && voice.note() == note,
+            };
+            if voice.is_gated() && matches {
+                voice.note_off();
+            }
+        }
+    }
+
+    /// Silences matching voices immediately.
+    pub fn choke(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        mut on_terminated: impl FnMut(&AdditiveVoice),
+    ) {
+        for voice in self.active_voices_mut() {
+            let matches = match voice_id {
+                Some(voice_id) => voice.voice_id() == voice_id,
+                None => voice.channel() == channel && voice.note() == note,
+            };
+            if matches {
+                on_terminated(voice);
+                voice.reset();
+            }
+        }
+    }
+
+    fn expression_slot(&self, channel: u8) -> usize {
+        if self.mpe {
+            channel as usize % MIDI_CHANNELS
+        } else {
+            0
+        }
+    }
+
+    /// Updates a channel's expression, and with it that of every note it applies to.
+    fn channel_expression(&mut self, channel: u8, update: impl Fn(&mut Expression)) {
+        let slot = self.expression_slot(channel);
+        update(&mut self.channel_expression[slot]);
+        update(&mut self.latest_expression);
+        for voice in &mut self.voices {
+            if !self.mpe || voice.channel() == channel {
+                update(&mut voice.expression);
+            }
+        }
+    }
+
+    pub fn midi_pitch_bend(&mut self, channel: u8, value: f32) {
+        if self.mpe && channel == MPE_MASTER_CHANNEL {
+            self.master_bend = value;
+            for voice in &mut self.voices {
+                voice.master_bend = value;
+            }
+            return;
+        }
+
+        self.channel_expression(channel, |expression| expression.bend = value);
+    }
+
+    pub fn midi_channel_pressure(&mut self, channel: u8, pressure: f32) {
+        self.channel_expression(channel, |expression| expression.pressure = pressure);
+    }
+
+    pub fn midi_timbre(&mut self, channel: u8, timbre: f32) {
+        self.channel_expression(channel, |expression| expression.timbre = timbre);
+    }
+
+    /// Updates a single note's expression, e.g. from polyphonic aftertouch or CLAP note
+    /// expressions.
+    pub fn note_expression(
+        &mut self,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+        update: impl Fn(&mut Expression),
+    ) {
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_active()) {
+            let matches = match voice_id {
+                Some(voice_id) => voice.voice_id() == voice_id,
+                None => voice.channel() == channel && voice.note() == note,
+            };
+            if matches {
+                update(&mut voice.expression);
+                update(&mut self.latest_expression);
+            }
+        }
+    }
+
+    fn allocate(&self, note: u8, polyphony: usize, stealing: &VoiceStealing) -> usize {
+        let voices = &self.voices[..polyphony];
+
+        if *stealing == VoiceStealing::SameNote {
+            if let Some(idx) = voices
+                .iter()
+                .position(|voice| voice.is_active() && voice.note() == note)
+            {
+                return idx;
+            }
+        }
+
+        if let Some(idx) = voices.iter().position(|voice| !voice.is_active()) {
+            return idx;
+        }
+
+        match stealing {
+            VoiceStealing::Quietest => (0..polyphony)
+                .min_by(|&a, &b| {
+                    let (a, b) = (&self.voices[a], &self.voices[b]);
+                    // released voices are stolen before held ones
+                    a.is_gated()
+                        .cmp(&b.is_gated())
+                        .then(a.envelope.value().total_cmp(&b.envelope.value()))
+                })
+                .unwrap(),
+            VoiceStealing::Oldest | VoiceStealing::SameNote => {
+                (0..polyphony).min_by_key(|&idx| self.ages[idx]).unwrap()
+            }
+        }
+    }
+
+    /// Renders every sounding voice, calling `on_terminated` for each one that finishes.
+    #[allow(clippy::too_many_arguments)]
+    pub fn process(
+        &mut self,
+        sample_rate: f32,
+        tuning: &Tuning,
+        ratios: &[f64; MAX_HARMONICS],
+        out_l: &mut [f32],
+        out_r: &mut [f32],
+        basic_gain_mode: &BasicGainMode,
+        slew: &SlewLimiter,
+        backend: &SynthesisBackend,
+        mut on_terminated: impl FnMut(&AdditiveVoice),
+    ) {
+        for voice in &mut self.voices {
+            let was_active = voice.is_active();
+
+            voice.process(
+                sample_rate,
+                tuning,
+                ratios,
+                out_l,
+                out_r,
+                basic_gain_mode,
+                slew,
+                backend,
+            );
+
+            if was_active && !voice.is_active() {
+                on_terminated(voice);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLYPHONY: usize = 2;
+
+    /// Plays `notes` in turn with voice IDs counting up from 1, returning the IDs of the voices
+    /// they took over.
+    fn play(pool: &mut VoicePool, notes: &[u8], stealing: &VoiceStealing) -> Vec<i32> {
+        let mut stolen = Vec::new();
+        for (voice_id, &note) in (1..).zip(notes) {
+            pool.note_on(voice_id, 0, note, 1.0, POLYPHONY, stealing, |voice| {
+                stolen.push(voice.voice_id())
+            });
+        }
+        stolen
+    }
+
+    fn advance_envelopes(pool: &mut VoicePool, samples: usize) {
+        let mut values = vec![0.0; samples];
+        for voice in &mut pool.voices {
+            voice.envelope.next_block(&mut values, samples);
+        }
+    }
+
+    #[test]
+    fn oldest_steals_in_the_order_notes_started() {
+        let mut pool = VoicePool::default();
+        let stolen = play(&mut pool, &[60, 62, 64, 65], &VoiceStealing::Oldest);
+        assert_eq!(stolen, [1, 2]);
+    }
+
+    #[test]
+    fn same_note_takes_over_the_voice_playing_it() {
+        let mut pool = VoicePool::default();
+        let stolen = play(&mut pool, &[60, 60, 62, 62], &VoiceStealing::SameNote);
+        assert_eq!(stolen, [1, 3]);
+
+        // other notes fall back to the oldest voice
+        let mut pool = VoicePool::default();
+        let stolen = play(&mut pool, &[60, 62, 64], &VoiceStealing::SameNote);
+        assert_eq!(stolen, [1]);
+    }
+
+    #[test]
+    fn quietest_steals_released_voices_first() {
+        let mut pool = VoicePool::default();
+        // a sample per millisecond, so the attack rises by 0.01 per sample
+        pool.set_envelope_times(1000.0, 100.0, 0.0, 0.0, 100.0);
+        pool.set_envelope_shape(
+            1.0,
+            &EnvelopeCurve::Linear,
+            &EnvelopeCurve::Linear,
+            &EnvelopeCurve::Linear,
+        );
+        let stealing = VoiceStealing::Quietest;
+        let mut stolen = Vec::new();
+        let mut on_terminated = |voice: &AdditiveVoice| stolen.push(voice.voice_id());
+
+        pool.note_on(1, 0, 60, 1.0, POLYPHONY, &stealing, &mut on_terminated);
+        advance_envelopes(&mut pool, 20);
+        pool.note_on(2, 0, 62, 1.0, POLYPHONY, &stealing, &mut on_terminated);
+        advance_envelopes(&mut pool, 10);
+        // the later note is still quieter
+        pool.note_on(3, 0, 64, 1.0, POLYPHONY, &stealing, &mut on_terminated);
+        advance_envelopes(&mut pool, 10);
+
+        // releasing the louder note makes it the one to go, even though it hasn't faded yet
+        pool.note_off(Some(1), 0, 60);
+        advance_envelopes(&mut pool, 1);
+        pool.note_on(4, 0, 65, 1.0, POLYPHONY, &stealing, &mut on_terminated);
+
+        assert_eq!(stolen, [2, 1]);
+    }
+
+    #[test]
+    fn mpe_member_bend_moves_only_its_own_note() {
+        let mut pool = VoicePool::default();
+        pool.set_mpe(true);
+        pool.note_on(1, 1, 60, 1.0, POLYPHONY, &VoiceStealing::Oldest, |_| {});
+        pool.note_on(2, 2, 64, 1.0, POLYPHONY, &VoiceStealing::Oldest, |_| {});
+
+        pool.midi_pitch_bend(1, 0.75);
+        assert_eq!(pool.voice_mut(1).unwrap().expression.bend, 0.75);
+        assert_eq!(pool.voice_mut(2).unwrap().expression.bend, 0.5);
+    }
+
+    #[test]
+    fn mpe_master_bend_moves_every_note() {
+        let mut pool = VoicePool::default();
+        pool.set_mpe(true);
+        pool.note_on(1, 1, 60, 1.0, POLYPHONY, &VoiceStealing::Oldest, |_| {});
+        pool.note_on(2, 2, 64, 1.0, POLYPHONY, &VoiceStealing::Oldest, |_| {});
+
+        pool.midi_pitch_bend(MPE_MASTER_CHANNEL, 0.25);
+        for voice_id in [1, 2] {
+            let voice = pool.voice_mut(voice_id).unwrap();
+            assert_eq!(voice.master_bend, 0.25);
+            assert_eq!(voice.expression.bend, 0.5);
+        }
+
+        // and notes that start later
+        pool.note_on(3, 3, 67, 1.0, POLYPHONY, &VoiceStealing::Oldest, |_| {});
+        assert_eq!(pool.voice_mut(3).unwrap().master_bend, 0.25);
+    }
+
+    #[test]
+    fn legato_notes_glide_from_the_previous_pitch() {
+        let mut pool = VoicePool::default();
+        pool.set_glide(1000.0, 100.0, &GlideMode::ConstantTime, true);
+        let stealing = VoiceStealing::Oldest;
+
+        pool.note_on(1, 0, 60, 1.0, POLYPHONY, &stealing, |_| {});
+        assert_eq!(pool.voice_mut(1).unwrap().pitch(), 60.0);
+        pool.note_on(2, 0, 64, 1.0, POLYPHONY, &stealing, |_| {});
+        assert_eq!(pool.voice_mut(2).unwrap().pitch(), 60.0);
+
+        // with nothing held, legato-only glide leaves the next note alone
+        pool.note_off(None, 0, 60);
+        pool.note_off(None, 0, 64);
+        pool.note_on(3, 0, 67, 1.0, POLYPHONY, &stealing, |_| {});
+        assert_eq!(pool.voice_mut(3).unwrap().pitch(), 67.0);
+    }
+}